@@ -0,0 +1,394 @@
+// src/api.rs
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Method, Request, Response};
+use crate::serve::{bind, header, header_value, LimitedReader};
+use crate::{generate_password, parse_expiry, parse_past_time, Actor, AddOutcome, Config, FileListing, FileShare};
+use crate::{InvalidInput, ListFilter, PermissionDenied, ShareInfo, ShareOptions, Store};
+
+/*
+REST API (`slink api`):
+- JSON over HTTP(S) below /api/v1, authenticated with `Authorization: Bearer TOKEN`
+  using tokens from `slink token create`
+- Each worker has its own `Store` whose actor is set to the token's user for every
+  request, so ownership checks and the audit log work as on the command line
+- Routes:
+    GET    /api/v1/files[?all=true&sort=size&...]    list files (ls), options as query parameters
+    POST   /api/v1/files?name=NAME[&dedupe=true]     add the request body (add -)
+    POST   /api/v1/files?name=NAME&encrypted=true    add a body encrypted by the client
+    GET    /api/v1/files/FILE                        file and shares (show)
+    DELETE /api/v1/files/FILE                        remove (rm)
+    POST   /api/v1/files/FILE/shares                 share, JSON body as `ShareRequest`
+    DELETE /api/v1/files/FILE/shares/RECIPIENT       unshare
+  FILE is a UUID, a name or name/N as on the command line, RECIPIENT may be @group
+- POST /api/v1/files bodies may be at most --max-upload MiB (413 otherwise)
+- Failed operations answer with {"error": "..."}: 400 for invalid requests
+  (`InvalidInput`), 403 if the token's user may not make the change
+  (`PermissionDenied`), 404 for unknown files and 500 for anything else
+*/
+
+const PREFIX: &str = "/api/v1";
+
+/// Largest JSON request body accepted.
+const MAX_BODY: u64 = 64 * 1024;
+
+/// Body of `POST /api/v1/files/FILE/shares`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShareRequest {
+    /// Recipient or @group
+    recipient: String,
+    /// Duration (30m, 12h, 7d, 2w) or timestamp, as with `share --expires`
+    expires: Option<String>,
+    max_downloads: Option<u32>,
+    password: Option<String>,
+    #[serde(default)]
+    generate_password: bool,
+    /// Fail unless the file is stored encrypted
+    #[serde(default)]
+    encrypt: bool,
+}
+
+/// A share as returned by the API, with its link.
+#[derive(Serialize)]
+struct SharedLink<'a> {
+    #[serde(flatten)]
+    share: &'a ShareInfo,
+    /// Without the `#key` of an encrypted file, which only the client has
+    url: String,
+    /// Generated passphrase, only when the share was just created with one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FileDetails<'a> {
+    file: &'a FileShare,
+    shares: Vec<SharedLink<'a>>,
+}
+
+#[derive(Serialize)]
+struct AddResponse<'a> {
+    file: &'a FileShare,
+    /// `dedupe` was requested and identical content was already stored
+    duplicate: bool,
+}
+
+/// A failed request: status code and message for the client.
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> ApiError {
+        ApiError { status, message: message.into() }
+    }
+}
+
+/// Errors of the store are reported as 403 if the token's user may not make
+/// the change, as 400 if the request was invalid and otherwise as 500, with
+/// the details only in the server's log.
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> ApiError {
+        if e.chain().any(|cause| cause.is::<PermissionDenied>()) {
+            ApiError::new(403, e.to_string())
+        } else if e.chain().any(|cause| cause.is::<InvalidInput>()) {
+            ApiError::new(400, e.to_string())
+        } else {
+            eprintln!("Internal error: {:#}", e);
+            ApiError::new(500, "Internal server error")
+        }
+    }
+}
+
+type Reply = std::result::Result<(u16, String), ApiError>;
+
+fn reply<T: Serialize>(status: u16, value: &T) -> Reply {
+    let body = serde_json::to_string_pretty(value).map_err(anyhow::Error::from)?;
+    Ok((status, body + "\n"))
+}
+
+pub fn serve(
+    config: Config,
+    listen: &str,
+    tls: Option<(String, String)>,
+    threads: usize,
+    max_upload: u64,
+) -> Result<()> {
+    let server = Arc::new(bind(listen, tls.as_ref())?);
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    println!("Serving the API on {}://{}{}", scheme, listen, PREFIX);
+
+    let workers = (0..threads.max(1)).map(|_| {
+        let server = Arc::clone(&server);
+        let config = config.clone();
+        thread::spawn(move || -> Result<()> {
+            // Each worker keeps its own store; rusqlite connections are not Sync
+            let mut store = Store::open(config)?;
+            for request in server.incoming_requests() {
+                handle_request(&mut store, request, max_upload);
+            }
+            Ok(())
+        })
+    }).collect::<Vec<_>>();
+
+    for worker in workers {
+        worker.join().map_err(|_| anyhow!("API worker panicked"))??;
+    }
+    Ok(())
+}
+
+fn handle_request(store: &mut Store, mut request: Request, max_upload: u64) {
+    let remote = request.remote_addr().map_or("-".to_string(), |a| a.ip().to_string());
+    let request_line = format!("{} {} HTTP/{}", request.method(), request.url(), request.http_version());
+    let user_agent = header_value(&request, "User-Agent").unwrap_or_else(|| "-".to_string());
+
+    let (user, reply) = match authenticate(store, &request, &remote) {
+        Ok(user) => (user, route(store, &mut request, max_upload)),
+        Err(e) => ("-".to_string(), Err(e)),
+    };
+    let (status, body) = match reply {
+        Ok((status, body)) => (status, body),
+        Err(e) => (e.status, format!("{}\n", json!({ "error": e.message }))),
+    };
+    let bytes = body.len();
+
+    let mut response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"));
+    if status == 401 {
+        response.add_header(header("WWW-Authenticate", "Bearer realm=\"slink\""));
+    }
+    if let Err(e) = request.respond(response) {
+        eprintln!("Error answering {}: {}", request_line, e);
+    }
+
+    // Combined log format, with the token's user
+    println!(
+        "{} - {} [{}] \"{}\" {} {} \"-\" \"{}\"",
+        remote,
+        user,
+        Utc::now().format("%d/%b/%Y:%H:%M:%S %z"),
+        request_line,
+        status,
+        bytes,
+        user_agent
+    );
+}
+
+/// Checks the bearer token and makes its user the actor of `store`. Returns
+/// the user name.
+fn authenticate(store: &mut Store, request: &Request, remote: &str) -> std::result::Result<String, ApiError> {
+    let secret = header_value(request, "Authorization")
+        .and_then(|value| {
+            let (scheme, token) = value.trim().split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+        })
+        .ok_or_else(|| ApiError::new(401, "Missing bearer token"))?;
+    let token = store.authenticate_token(&secret)?
+        .ok_or_else(|| ApiError::new(401, "Invalid token"))?;
+
+    let path = request.url().split('?').next().unwrap_or_default();
+    store.set_actor(Actor {
+        user: token.user.clone(),
+        sudo: false,
+        ssh_client: None,
+        remote_addr: Some(remote.to_string()),
+        command: format!("api {} {} (token {})", request.method(), path, token.name),
+    });
+    Ok(token.user)
+}
+
+fn route(store: &Store, request: &mut Request, max_upload: u64) -> Reply {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let path = path.strip_prefix(PREFIX)
+        .filter(|path| path.starts_with('/'))
+        .ok_or_else(|| ApiError::new(404, "Not found"))?;
+    let segments = path.trim_start_matches('/').trim_end_matches('/').split('/')
+        .map(|segment| decode(segment, false))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    match (request.method(), segments.as_slice()) {
+        (Method::Get, ["files"]) => list(store, query),
+        (Method::Post, ["files"]) => add(store, request, query, max_upload),
+        (Method::Get, ["files", file]) => show(store, file),
+        (Method::Delete, ["files", file]) => remove(store, file),
+        (Method::Post, ["files", file, "shares"]) => share(store, request, file),
+        (Method::Delete, ["files", file, "shares", recipient]) => unshare(store, file, recipient),
+        (_, ["files"]) | (_, ["files", _]) | (_, ["files", _, "shares"]) | (_, ["files", _, "shares", _]) => {
+            Err(ApiError::new(405, "Method not allowed"))
+        }
+        _ => Err(ApiError::new(404, "Not found")),
+    }
+}
+
+/// Takes the options of `slink ls` as query parameters, e.g.
+/// `?name=*.pdf&no_shares=true&sort=size&limit=20`.
+fn list(store: &Store, query: &str) -> Reply {
+    let filter = ListFilter {
+        all: query_flag(query, "all")?,
+        recipient: query_value(query, "recipient")?,
+        since: query_value(query, "since")?.as_deref().map(parse_past_time).transpose()?,
+        until: query_value(query, "until")?.as_deref().map(parse_past_time).transpose()?,
+        active_only: query_flag(query, "active_only")?,
+        no_shares: query_flag(query, "no_shares")?,
+        name: query_value(query, "name")?,
+        sort: query_value(query, "sort")?.as_deref().map(str::parse).transpose()?.unwrap_or_default(),
+        reverse: query_flag(query, "reverse")?,
+        limit: query_number(query, "limit")?,
+        offset: query_number(query, "offset")?.unwrap_or(0),
+    };
+    if filter.active_only && filter.no_shares {
+        return Err(ApiError::new(400, "Give either active_only or no_shares"));
+    }
+    let files: Vec<FileListing> = store.list(&filter)?;
+    reply(200, &files)
+}
+
+/// Adds the request body, refusing bodies larger than `max_upload` bytes.
+fn add(store: &Store, request: &mut Request, query: &str, max_upload: u64) -> Reply {
+    let name = query_value(query, "name")?
+        .ok_or_else(|| ApiError::new(400, "Missing name parameter"))?;
+    let dedupe = query_flag(query, "dedupe")?;
+    let too_large = || ApiError::new(413, format!("Request body larger than {} bytes", max_upload));
+    if request.body_length().is_some_and(|length| length as u64 > max_upload) {
+        return Err(too_large());
+    }
+    let mut body = LimitedReader::new(request.as_reader(), max_upload);
+
+    if query_flag(query, "encrypted")? {
+        let file = store.add_encrypted(&mut body, &name)
+            .map_err(|e| if body.exceeded { too_large() } else { e.into() })?;
+        return reply(201, &AddResponse { file: &file, duplicate: false });
+    }
+    let outcome = store.add_reader(&mut body, &name, dedupe)
+        .map_err(|e| if body.exceeded { too_large() } else { e.into() })?;
+    let (status, duplicate) = match &outcome {
+        AddOutcome::Added { .. } => (201, false),
+        AddOutcome::Duplicate(_) => (200, true),
+    };
+    let body = AddResponse { file: outcome.file(), duplicate };
+    reply(status, &body)
+}
+
+fn show(store: &Store, file_spec: &str) -> Reply {
+    let file = resolve(store, file_spec)?;
+    let shares = store.shares(&file.uuid)?;
+    let details = FileDetails {
+        file: &file,
+        shares: shares.iter().map(|share| SharedLink {
+            share,
+            url: store.share_url(&file, &share.share_hash),
+            password: None,
+        }).collect(),
+    };
+    reply(200, &details)
+}
+
+fn remove(store: &Store, file_spec: &str) -> Reply {
+    let file = resolve(store, file_spec)?;
+    let file = store.remove(&file.uuid)?;
+    reply(200, &json!({ "file": file }))
+}
+
+/// Shares with one recipient or every member of a group; like the command
+/// line, each member gets their own generated passphrase.
+fn share(store: &Store, request: &mut Request, file_spec: &str) -> Reply {
+    let mut body = String::new();
+    request.as_reader().take(MAX_BODY).read_to_string(&mut body)
+        .map_err(|e| ApiError::new(400, format!("Failed to read request body: {}", e)))?;
+    let share_request: ShareRequest = serde_json::from_str(&body)
+        .map_err(|e| ApiError::new(400, format!("Invalid request body: {}", e)))?;
+    if share_request.password.is_some() && share_request.generate_password {
+        return Err(ApiError::new(400, "Give either password or generate_password"));
+    }
+
+    let file = resolve(store, file_spec)?;
+    let expires = share_request.expires.as_deref().map(parse_expiry).transpose()?;
+    let recipients = store.expand_recipients(&share_request.recipient)?;
+
+    let mut shares = Vec::new();
+    for recipient in &recipients {
+        let password = if share_request.generate_password {
+            Some(generate_password())
+        } else {
+            share_request.password.clone()
+        };
+        let options = ShareOptions {
+            expires,
+            max_downloads: share_request.max_downloads,
+            password: password.clone(),
+            encrypt: share_request.encrypt,
+        };
+        let share = store.share(&file.uuid, recipient, &options)?;
+        shares.push((share, password.filter(|_| share_request.generate_password)));
+    }
+
+    let links: Vec<SharedLink> = shares.iter().map(|(share, password)| SharedLink {
+        share,
+        url: store.share_url(&file, &share.share_hash),
+        password: password.clone(),
+    }).collect();
+    reply(201, &links)
+}
+
+fn unshare(store: &Store, file_spec: &str, recipient: &str) -> Reply {
+    let file = resolve(store, file_spec)?;
+    let recipients = store.expand_recipients(recipient)?;
+    for recipient in &recipients {
+        store.unshare(&file.uuid, recipient)?;
+    }
+    reply(200, &json!({ "unshared": recipients }))
+}
+
+/// Unknown files and invalid file specifications yield 404.
+fn resolve(store: &Store, file_spec: &str) -> std::result::Result<FileShare, ApiError> {
+    store.resolve(file_spec).map_err(|e| match ApiError::from(e) {
+        ApiError { status: 400, message } => ApiError::new(404, message),
+        error => error,
+    })
+}
+
+fn query_value(query: &str, name: &str) -> std::result::Result<Option<String>, ApiError> {
+    query.split('&')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key == name).then_some(value)
+        })
+        .next()
+        .map(|value| decode(value, true))
+        .transpose()
+}
+
+fn query_flag(query: &str, name: &str) -> std::result::Result<bool, ApiError> {
+    match query_value(query, name)?.as_deref() {
+        None | Some("false") | Some("0") => Ok(false),
+        Some("") | Some("true") | Some("1") => Ok(true),
+        Some(value) => Err(ApiError::new(400, format!("Invalid value for {}: {}", name, value))),
+    }
+}
+
+fn query_number(query: &str, name: &str) -> std::result::Result<Option<u64>, ApiError> {
+    query_value(query, name)?
+        .map(|value| value.parse()
+            .map_err(|_| ApiError::new(400, format!("Invalid value for {}: {}", name, value))))
+        .transpose()
+}
+
+/// Percent-decodes a path segment or, with `form`, a query value in which `+`
+/// stands for a space.
+fn decode(value: &str, form: bool) -> std::result::Result<String, ApiError> {
+    let value = if form { value.replace('+', " ") } else { value.to_string() };
+    percent_decode_str(&value).decode_utf8()
+        .map(|decoded| decoded.to_string())
+        .map_err(|_| ApiError::new(400, "Invalid UTF-8 in URL"))
+}
@@ -0,0 +1,421 @@
+// src/audit.rs
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use serde::Serialize;
+
+/*
+Audit log:
+- Every change to files, shares, groups and the configuration appends a row to
+  the `audit` table, recording who made it and from where
+- Each row stores the BLAKE3 hash of its own fields and of the previous row's
+  hash, so editing, deleting or reordering rows breaks the chain
+- AUTOINCREMENT keeps `sqlite_sequence` at the highest ID ever used, which
+  also reveals rows removed from the end
+- Anyone with write access to the database can still rewrite the whole chain;
+  keep a copy of the head hash printed by `slink audit verify` elsewhere to
+  detect that
+*/
+
+/// `prev_hash` of the first entry.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Who is making changes, recorded with every audit entry and checked
+/// against file ownership.
+#[derive(Debug, Clone, Serialize)]
+pub struct Actor {
+    /// Unix user; the invoking user when run as root through sudo
+    pub user: String,
+    /// Whether the user is acting through sudo
+    pub sudo: bool,
+    /// Client address when run over SSH
+    pub ssh_client: Option<String>,
+    /// Client address of an HTTP request, for `slink api` and uploads to
+    /// `slink serve`
+    pub remote_addr: Option<String>,
+    /// Command line, e.g. `slink share alice@example.com report.pdf`
+    pub command: String,
+}
+
+impl Actor {
+    /// Describes the current process.
+    pub fn current() -> Actor {
+        let user = users::get_current_username()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| nix::unistd::getuid().to_string());
+        // Operators may have to run slink through sudo to hand files to the web
+        // user; what they do is still theirs
+        let sudo_user = std::env::var("SUDO_USER").ok()
+            .filter(|sudo_user| nix::unistd::getuid().is_root() && *sudo_user != user);
+        let (user, sudo) = match sudo_user {
+            Some(sudo_user) => (sudo_user, true),
+            None => (user, false),
+        };
+
+        // Both variables start with the client address
+        let ssh_client = ["SSH_CLIENT", "SSH_CONNECTION"].iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .find_map(|value| value.split_whitespace().next().map(str::to_string));
+
+        let command = std::env::args().enumerate()
+            .map(|(i, arg)| if i == 0 {
+                std::path::Path::new(&arg).file_name()
+                    .map_or(arg.clone(), |name| name.to_string_lossy().to_string())
+            } else {
+                quote_arg(&arg)
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        Actor { user, sudo, ssh_client, remote_addr: None, command }
+    }
+
+    /// The user as shown in the audit log.
+    fn describe(&self) -> String {
+        if self.sudo {
+            format!("{} (sudo)", self.user)
+        } else {
+            self.user.clone()
+        }
+    }
+}
+
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '\'' || c == '"') {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// What changed. `action` names the operation, e.g. `share` or `rm`.
+#[derive(Debug, Default)]
+pub struct Event {
+    pub action: &'static str,
+    pub uuid: Option<String>,
+    pub filename: Option<String>,
+    pub recipient: Option<String>,
+    pub share_hash: Option<String>,
+    pub details: Option<String>,
+}
+
+/// A recorded audit entry.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub user: String,
+    pub ssh_client: Option<String>,
+    pub command: String,
+    pub action: String,
+    pub uuid: Option<String>,
+    pub filename: Option<String>,
+    pub recipient: Option<String>,
+    pub share_hash: Option<String>,
+    pub details: Option<String>,
+    pub remote_addr: Option<String>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    const COLUMNS: &'static str = "id, timestamp, user, ssh_client, command, action, uuid, filename,
+        recipient, share_hash, details, prev_hash, hash, remote_addr";
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<AuditEntry> {
+        Ok(AuditEntry {
+            id: row.get(0)?,
+            timestamp: row.get(1)?,
+            user: row.get(2)?,
+            ssh_client: row.get(3)?,
+            command: row.get(4)?,
+            action: row.get(5)?,
+            uuid: row.get(6)?,
+            filename: row.get(7)?,
+            recipient: row.get(8)?,
+            share_hash: row.get(9)?,
+            details: row.get(10)?,
+            prev_hash: row.get(11)?,
+            hash: row.get(12)?,
+            remote_addr: row.get(13)?,
+        })
+    }
+
+    /// Hash over the previous hash and every field. Each field is length
+    /// prefixed, and absent fields are distinct from empty ones. `remote_addr`,
+    /// added later, is only hashed when present so older entries still verify.
+    fn compute_hash(&self) -> String {
+        let timestamp = self.timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let fields = [
+            Some(self.prev_hash.as_str()),
+            Some(&self.id.to_string()),
+            Some(&timestamp),
+            Some(&self.user),
+            self.ssh_client.as_deref(),
+            Some(&self.command),
+            Some(&self.action),
+            self.uuid.as_deref(),
+            self.filename.as_deref(),
+            self.recipient.as_deref(),
+            self.share_hash.as_deref(),
+            self.details.as_deref(),
+        ];
+        let remote_addr = self.remote_addr.as_deref().map(Some);
+
+        let mut hasher = blake3::Hasher::new_derive_key("slink audit log");
+        for field in fields.into_iter().chain(remote_addr) {
+            match field {
+                Some(value) => {
+                    hasher.update(&[1]);
+                    hasher.update(&(value.len() as u64).to_le_bytes());
+                    hasher.update(value.as_bytes());
+                }
+                None => {
+                    hasher.update(&[0]);
+                }
+            }
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+}
+
+/// Result of checking the hash chain.
+#[derive(Debug, Serialize)]
+pub struct ChainVerification {
+    pub entries: usize,
+    /// Hash of the newest entry
+    pub head: Option<String>,
+    pub problems: Vec<String>,
+}
+
+/// Runs `change` in one transaction with the audit entries it records, so
+/// that neither is committed without the other. Joins a transaction that is
+/// already open.
+pub fn with_transaction<T>(conn: &Connection, change: impl FnOnce() -> Result<T>) -> Result<T> {
+    if !conn.is_autocommit() {
+        return change();
+    }
+    // IMMEDIATE, so that reading the head of the log and appending to it does
+    // not interleave with another writer; dropping the transaction on error
+    // rolls it back
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let value = change()?;
+    tx.commit()?;
+    Ok(value)
+}
+
+/// Appends `event` to the audit log.
+pub fn record(conn: &Connection, actor: &Actor, event: Event) -> Result<()> {
+    with_transaction(conn, || append(conn, actor, event))
+}
+
+fn append(conn: &Connection, actor: &Actor, event: Event) -> Result<()> {
+    let head = conn.query_row(
+        "SELECT id, hash FROM audit ORDER BY id DESC LIMIT 1",
+        [],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+    ).optional()?;
+    let sequence = conn.query_row(
+        "SELECT seq FROM sqlite_sequence WHERE name = 'audit'",
+        [],
+        |row| row.get::<_, i64>(0),
+    ).optional()?.unwrap_or(0);
+    let (last_id, prev_hash) = head.unwrap_or((0, GENESIS.to_string()));
+
+    let mut entry = AuditEntry {
+        id: last_id.max(sequence) + 1,
+        timestamp: Utc::now(),
+        user: actor.describe(),
+        ssh_client: actor.ssh_client.clone(),
+        command: actor.command.clone(),
+        action: event.action.to_string(),
+        uuid: event.uuid,
+        filename: event.filename,
+        recipient: event.recipient,
+        share_hash: event.share_hash,
+        details: event.details,
+        remote_addr: actor.remote_addr.clone(),
+        prev_hash,
+        hash: String::new(),
+    };
+    entry.hash = entry.compute_hash();
+
+    conn.execute(
+        &format!("INSERT INTO audit ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            AuditEntry::COLUMNS),
+        params![
+            entry.id,
+            entry.timestamp,
+            entry.user,
+            entry.ssh_client,
+            entry.command,
+            entry.action,
+            entry.uuid,
+            entry.filename,
+            entry.recipient,
+            entry.share_hash,
+            entry.details,
+            entry.prev_hash,
+            entry.hash,
+            entry.remote_addr
+        ],
+    )?;
+    Ok(())
+}
+
+/// Entries oldest first, optionally only those about file `uuid` or share
+/// hash `share_hash`.
+pub fn entries(conn: &Connection, uuid: Option<&str>, share_hash: Option<&str>) -> Result<Vec<AuditEntry>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM audit
+         WHERE (?1 IS NULL OR uuid = ?1) AND (?2 IS NULL OR share_hash = ?2 OR instr(details, ?2) > 0)
+         ORDER BY id",
+        AuditEntry::COLUMNS
+    ))?;
+    let entries = stmt.query_map(params![uuid, share_hash], AuditEntry::from_row)?;
+    entries.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+}
+
+/// Recomputes every hash and checks that each entry follows the one before.
+pub fn verify(conn: &Connection) -> Result<ChainVerification> {
+    let entries = entries(conn, None, None)?;
+    let mut problems = Vec::new();
+
+    let mut expected_prev = GENESIS.to_string();
+    let mut previous_id = 0;
+    for entry in &entries {
+        // IDs are assigned consecutively, so a gap means deleted entries
+        if entry.id != previous_id + 1 {
+            problems.push(format!("{} missing", describe_range(previous_id + 1, entry.id - 1)));
+        } else if entry.prev_hash != expected_prev {
+            problems.push(format!("Entry {} does not follow the entry before it", entry.id));
+        }
+        if entry.compute_hash() != entry.hash {
+            problems.push(format!("Entry {} was modified", entry.id));
+        }
+        // Continue from the stored hash so one bad entry is reported once
+        expected_prev = entry.hash.clone();
+        previous_id = entry.id;
+    }
+
+    let sequence = conn.query_row(
+        "SELECT seq FROM sqlite_sequence WHERE name = 'audit'",
+        [],
+        |row| row.get::<_, i64>(0),
+    ).optional()?.unwrap_or(0);
+    if sequence > previous_id {
+        problems.push(format!("{} missing from the end of the log",
+            describe_range(previous_id + 1, sequence)));
+    }
+
+    Ok(ChainVerification {
+        entries: entries.len(),
+        head: entries.last().map(|entry| entry.hash.clone()),
+        problems,
+    })
+}
+
+fn describe_range(first: i64, last: i64) -> String {
+    if first == last {
+        format!("Entry {} is", first)
+    } else {
+        format!("Entries {} to {} are", first, last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+
+    fn actor(remote_addr: Option<&str>) -> Actor {
+        Actor {
+            user: "alice".to_string(),
+            sudo: false,
+            ssh_client: None,
+            remote_addr: remote_addr.map(str::to_string),
+            command: "slink share bob@example.com report.pdf".to_string(),
+        }
+    }
+
+    /// A database with entries 1 to 4, the second and fourth from an HTTP client.
+    fn log() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut conn).unwrap();
+        for remote_addr in [None, Some("192.0.2.7"), None, Some("2001:db8::1")] {
+            record(&conn, &actor(remote_addr), Event {
+                action: "share",
+                recipient: Some("bob@example.com".to_string()),
+                ..Event::default()
+            }).unwrap();
+        }
+        conn
+    }
+
+    fn problems(conn: &Connection) -> Vec<String> {
+        verify(conn).unwrap().problems
+    }
+
+    #[test]
+    fn entries_with_and_without_remote_addr_chain() {
+        let conn = log();
+        let verification = verify(&conn).unwrap();
+        assert_eq!(verification.entries, 4);
+        assert!(verification.problems.is_empty(), "{:?}", verification.problems);
+
+        let entries = entries(&conn, None, None).unwrap();
+        assert_eq!(entries[0].prev_hash, GENESIS);
+        assert_eq!(entries[1].remote_addr.as_deref(), Some("192.0.2.7"));
+        assert_eq!(entries[2].prev_hash, entries[1].hash);
+        assert_eq!(verification.head.as_deref(), Some(entries[3].hash.as_str()));
+    }
+
+    #[test]
+    fn detects_a_changed_row() {
+        let conn = log();
+        conn.execute("UPDATE audit SET recipient = 'mallory@example.com' WHERE id = 2", []).unwrap();
+        assert_eq!(problems(&conn), ["Entry 2 was modified"]);
+    }
+
+    #[test]
+    fn detects_a_changed_remote_addr() {
+        let conn = log();
+        conn.execute("UPDATE audit SET remote_addr = '198.51.100.1' WHERE id = 2", []).unwrap();
+        conn.execute("UPDATE audit SET remote_addr = '198.51.100.1' WHERE id = 3", []).unwrap();
+        assert_eq!(problems(&conn), ["Entry 2 was modified", "Entry 3 was modified"]);
+    }
+
+    #[test]
+    fn detects_a_deleted_row() {
+        let conn = log();
+        conn.execute("DELETE FROM audit WHERE id = 2", []).unwrap();
+        assert_eq!(problems(&conn), ["Entry 2 is missing"]);
+    }
+
+    #[test]
+    fn detects_rows_deleted_from_the_end() {
+        let conn = log();
+        conn.execute("DELETE FROM audit WHERE id >= 3", []).unwrap();
+        assert_eq!(problems(&conn), ["Entries 3 to 4 are missing from the end of the log"]);
+    }
+
+    #[test]
+    fn detects_a_gap_in_the_sequence() {
+        let conn = log();
+        // Renumbering hides the deletion from the ID check, not from the chain
+        conn.execute("DELETE FROM audit WHERE id = 3", []).unwrap();
+        conn.execute("UPDATE audit SET id = 3 WHERE id = 4", []).unwrap();
+        assert_eq!(problems(&conn), [
+            "Entry 3 does not follow the entry before it",
+            "Entry 3 was modified",
+            "Entry 4 is missing from the end of the log",
+        ]);
+    }
+
+    #[test]
+    fn appends_after_the_highest_id_ever_used() {
+        let conn = log();
+        conn.execute("DELETE FROM audit WHERE id = 4", []).unwrap();
+        record(&conn, &actor(None), Event { action: "rm", ..Event::default() }).unwrap();
+        assert_eq!(problems(&conn), ["Entry 4 is missing"]);
+    }
+}
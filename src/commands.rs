@@ -2,23 +2,35 @@
 use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use prettytable::{Table, row};
-use rusqlite::{Connection, params};
+use rusqlite::Connection;
+use std::fs::{self, create_dir_all};
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
-use dirs::config_dir;
-use std::fs;
 use std::path::PathBuf;
-use crate::{init_database, create_dir_all};
-use crate::{Config, FileShare, ShareInfo};
-use crate::Uuid;
-use crate::{Permissions, PermissionsExt, set_permissions, set_permissions_recursive};
-use std::io::{self, Write, Read, BufReader, BufWriter};
-use tempfile::NamedTempFile;
-
-pub fn initialize_config() -> Result<()> {
-    let config_dir = config_dir()
-        .ok_or_else(|| anyhow!("Could not determine config directory"))?
-        .join("slink");
-    let config_path = config_dir.join("slink.conf");
+use slink::{audit, crypto, generate_password, init_database, mail, migrations, parse_expiry, storage};
+use slink::fsck::RepairOutcome;
+use slink::audit::Event;
+use slink::mail::ShareNotification;
+use slink::{Actor, AddOutcome, Config, SYSTEM_CONFIG_PATH, FileListing, FileShare, ListFilter, ShareInfo, ShareOptions, Statistics, Store, Verification};
+use slink::UploadRequest;
+use crate::{InitFlags, OutputFormat, ShareFlags};
+use uuid::Uuid;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use globset::GlobBuilder;
+use walkdir::WalkDir;
+
+/// Creates the configuration at `config_path` (from `--config` or
+/// SLINK_CONFIG) or the per-user default, or with `system` the one in
+/// /etc/slink shared by every operator on the machine. Values in `flags` are
+/// not prompted for, nor are any others with `--yes`.
+pub fn initialize_config(config_path: Option<PathBuf>, system: bool, flags: &InitFlags) -> Result<()> {
+    let config_path = match config_path {
+        _ if system => PathBuf::from(SYSTEM_CONFIG_PATH),
+        Some(path) => path,
+        None => Config::user_path()?,
+    };
 
     if config_path.exists() {
         return Err(anyhow!("Configuration file already exists at {}", config_path.display()));
@@ -26,53 +38,55 @@ pub fn initialize_config() -> Result<()> {
 
     println!("Initializing configuration...");
 
-    // Prompt for each configuration value
-    let base_url = prompt_with_default("Base URL", "http://localhost:8080")?;
-    let base_dir = prompt_with_validation("Base directory", "/var/www", |input| {
-        let path = Path::new(input);
-        if path.exists() && path.is_dir() {
-            Ok(())
-        } else {
-            Err("Base directory must exist and be a valid directory")
+    // Prompt for each configuration value not given on the command line
+    let base_url = answer("Base URL", "http://localhost:8080", flags.base_url.as_deref(), flags.yes,
+        Config::check_base_url)?;
+    let base_dir = answer("Base directory", "/var/www", flags.base_dir.as_deref(), flags.yes,
+        Config::check_base_dir)?;
+    let default_db_path = if system {
+        PathBuf::from("/var/lib/slink/shares.db")
+    } else {
+        dirs::data_dir()
+            .ok_or_else(|| anyhow!("Could not determine data directory"))?
+            .join("slink")
+            .join("shares.db")
+    };
+    let db_path = answer("Database path", &default_db_path.to_string_lossy(), flags.db_path.as_deref(),
+        flags.yes, |_| Ok(()))?;
+    let hash_secret = match &flags.secret_file {
+        Some(path) => {
+            let secret = fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read secret file {}: {}", path.display(), e))?;
+            Config::check_hash_secret(&secret)
+                .map_err(|err| anyhow!("{} (in secret file {})", err, path.display()))?;
+            secret.trim().to_string()
         }
-    })?;
-    let db_path = prompt_with_default("Database path", &dirs::data_dir()
-        .ok_or_else(|| anyhow!("Could not determine data directory"))?
-        .join("slink")
-        .join("shares.db")
-        .to_string_lossy())?;
-    let hash_secret = prompt_with_default("Hash secret (leave empty to generate)", "*generate*")?;
+        None if flags.yes => "*generate*".to_string(),
+        None => prompt_with_validation("Hash secret (leave empty to generate)", "*generate*", |input| {
+            match input {
+                "*generate*" | "" => Ok(()),
+                input => Config::check_hash_secret(input),
+            }
+        })?,
+    };
     let hash_secret = if hash_secret == "*generate*" || hash_secret.is_empty() {
         // Generate a random password
         Uuid::new_v4().to_string()
     } else {
         hash_secret
     };
-    let web_user = prompt_with_validation("Web user", "www-data", |input| {
-        if users::get_user_by_name(input).is_some() {
-            Ok(())
-        } else {
-            Err("Web user must exist")
-        }
-    })?;
-    let web_group = prompt_with_validation("Web group", "www-data", |input| {
-        if users::get_group_by_name(input).is_some() {
-            Ok(())
-        } else {
-            Err("Web group must exist")
-        }
-    })?;
-    let hash_bytes = prompt_with_validation("Hash bytes (2-32)", "7", |input| {
-        input.parse::<usize>()
-            .map_err(|_| "Hash bytes must be a number")
-            .and_then(|value| {
-                if (2..=32).contains(&value) {
-                    Ok(())
-                } else {
-                    Err("Hash bytes must be between 2 and 32")
-                }
-            })
-    })?.parse::<usize>()?;
+    let web_user = answer("Web user", "www-data", flags.web_user.as_deref(), flags.yes,
+        Config::check_web_user)?;
+    let web_group = answer("Web group", "www-data", flags.web_group.as_deref(), flags.yes,
+        Config::check_web_group)?;
+    let hash_bytes = answer("Hash bytes (2-32)", "7", flags.hash_bytes.as_deref(), flags.yes,
+        Config::check_hash_bytes)?.parse::<usize>()?;
+    let admin_group = if system {
+        Some(answer("Admin group", "root", flags.admin_group.as_deref(), flags.yes,
+            Config::check_admin_group)?)
+    } else {
+        None
+    };
 
     // Create configuration
     let config = Config {
@@ -83,25 +97,35 @@ pub fn initialize_config() -> Result<()> {
         web_user,
         web_group,
         hash_bytes,
+        admin_group,
+        mail: None,
+        storage: None,
+        profile: None,
     };
 
-    // Create config directory and write the configuration file
-    create_dir_all(&config_dir)
-        .map_err(|e| anyhow!("Failed to create config directory: {}", e))?;
-    fs::write(&config_path, toml::to_string(&config)?)
-        .map_err(|e| anyhow!("Failed to write config file {}: {}", config_path.display(), e))?;
-
-    // Set permissions to 0600
-    set_permissions(&config_path, Permissions::from_mode(0o600))
-        .map_err(|e| anyhow!("Failed to set permissions on config file {}: {}", config_path.display(), e))?;
+    // Write the configuration file with permissions 0600, or 0640 so that the
+    // operators' group can read the system-wide one
+    config.save(&config_path)?;
+    if system {
+        fs::set_permissions(&config_path, fs::Permissions::from_mode(0o640))?;
+    }
 
     // Initialize the database
     create_dir_all(Path::new(&config.db_path).parent().unwrap())
         .map_err(|e| anyhow!("Failed to create database directory: {}", e))?;
     init_database(&config.db_path)
         .map_err(|e| anyhow!("Failed to initialize database {}: {}", config.db_path, e))?;
+    audit::record(&Connection::open(&config.db_path)?, &Actor::current(), Event {
+        action: "init",
+        details: Some(format!("new configuration and hash secret, base directory {}", config.base_dir)),
+        ..Event::default()
+    })?;
 
     println!("Configuration saved to {}", config_path.display());
+    if system {
+        println!("Give the operators' group read access to it and write access to {} \
+            and its directory, e.g. with chgrp and chmod g+rw", config.db_path);
+    }
     Ok(())
 }
 
@@ -114,9 +138,24 @@ fn prompt_with_default(prompt: &str, default: &str) -> Result<String> {
     Ok(if input.is_empty() { default.to_string() } else { input.to_string() })
 }
 
+/// `given` if set, otherwise with `yes` the default, failing if the value does
+/// not pass `validate`; otherwise prompts like `prompt_with_validation`.
+fn answer<F>(prompt: &str, default: &str, given: Option<&str>, yes: bool, validate: F) -> Result<String>
+where
+    F: Fn(&str) -> Result<(), &'static str>,
+{
+    match given.or(yes.then_some(default)) {
+        Some(value) => {
+            validate(value).map_err(|err| anyhow!("{} (got {})", err, value))?;
+            Ok(value.to_string())
+        }
+        None => prompt_with_validation(prompt, default, validate),
+    }
+}
+
 fn prompt_with_validation<F>(prompt: &str, default: &str, validate: F) -> Result<String>
 where
-    F: Fn(&str) -> Result<(), &str>,
+    F: Fn(&str) -> Result<(), &'static str>,
 {
     loop {
         let input = prompt_with_default(prompt, default)?;
@@ -128,358 +167,1202 @@ where
     }
 }
 
-pub fn add_file(config: &Config, file_path: &str, name: Option<String>) -> Result<String> {
-    let conn = Connection::open(&config.db_path)?;
+/// How `add` treats the data it reads.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encryption {
+    None,
+    /// Encrypt each file with a new key before it is stored (`--encrypt`)
+    Encrypt,
+    /// stdin is already encrypted, by `slink add --encrypt` in remote mode
+    Encrypted,
+}
+
+/// A file stored by `add`, as printed with `--format json`; remote mode reads
+/// the UUID and checksum from it.
+#[derive(Serialize)]
+pub struct Added {
+    pub file: FileShare,
+    /// `--dedupe` found identical content, which `file` is, and added nothing
+    pub duplicate: bool,
+    /// Key of a file encrypted with `--encrypt`; it is not stored anywhere
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+/// Adds every file named by `paths`, each under its own UUID, and returns
+/// them in order. See `expand_add_paths` for how paths are interpreted. With
+/// `--format json` the results are printed as one array at the end.
+pub fn add_files(
+    store: &Store,
+    paths: &[String],
+    recursive: bool,
+    name: Option<String>,
+    dedupe: bool,
+    encryption: Encryption,
+    format: OutputFormat,
+) -> Result<Vec<Added>> {
+    let files = expand_add_paths(paths, recursive)?;
+    if files.len() > 1 && name.is_some() {
+        return Err(anyhow!("--name can only be used when adding a single file"));
+    }
+    let table = format != OutputFormat::Json;
 
-    // Enable WAL mode for better concurrency
-    conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+    let mut added = Vec::new();
+    for file in &files {
+        if files.len() > 1 && table {
+            println!("{}:", file.path);
+        }
+        let name = name.as_deref().or(file.name.as_deref());
+        added.push(match encryption {
+            Encryption::None => add_file(store, &file.path, name, dedupe, table)?,
+            _ => add_encrypted_file(store, &file.path, name, encryption, table)?,
+        });
+    }
+    if !table {
+        print_json(&added)?;
+    } else if files.len() > 1 {
+        println!("{} files processed", added.len());
+    }
+    Ok(added)
+}
+
+/// A file to add. Files found below a directory are stored under their path
+/// from that directory down, with `/` replaced by `_`, e.g.
+/// `project_src_main.rs`.
+pub struct AddPath {
+    pub path: String,
+    pub name: Option<String>,
+}
+
+/// Turns the paths given to `add` into a list of files. Patterns containing
+/// `*`, `?`, `[` or `{` are expanded here so they also work when quoted, e.g.
+/// over SSH. Directories are walked with `recursive`, skipping hidden entries;
+/// otherwise a directory argument is an error and directories matched by a
+/// pattern are skipped. Symlinks are only followed when named directly, so a
+/// walk cannot leave the directory.
+pub fn expand_add_paths(paths: &[String], recursive: bool) -> Result<Vec<AddPath>> {
+    if paths.iter().any(|p| p == "-") {
+        if paths.len() > 1 {
+            return Err(anyhow!("Reading from stdin (-) cannot be combined with other files"));
+        }
+        return Ok(vec![AddPath { path: "-".to_string(), name: None }]);
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        let is_pattern = path.contains(['*', '?', '[', '{']) && !Path::new(path).exists();
+        if !is_pattern {
+            let path = PathBuf::from(path);
+            if path.is_dir() && !recursive {
+                return Err(anyhow!("{} is a directory (use -r to add the files in it)", path.display()));
+            }
+            collect_files(&path, &mut files)?;
+            continue;
+        }
+
+        let matches = glob_matches(path)?;
+        if matches.is_empty() {
+            return Err(anyhow!("No files match {}", path));
+        }
+        for matched in matches {
+            if matched.symlink_metadata()?.file_type().is_symlink() {
+                eprintln!("Skipping symlink {}", matched.display());
+                continue;
+            }
+            if matched.is_dir() && !recursive {
+                eprintln!("Skipping directory {}", matched.display());
+                continue;
+            }
+            collect_files(&matched, &mut files)?;
+        }
+    }
 
-    // Sanitize and validate the provided name or get from file_path
-    let filename = if let Some(name) = name {
-        sanitize_filename(&name)?
+    // A file can be named more than once, e.g. by `dir/**` together with -r
+    let mut seen = std::collections::HashSet::new();
+    files.retain(|(f, _)| seen.insert(f.clone()));
+
+    Ok(files.into_iter()
+        .map(|(path, name)| AddPath { path: path.to_string_lossy().to_string(), name })
+        .collect())
+}
+
+/// Appends `path` if it is a file, or every non-hidden file below it if it is
+/// a directory, together with the name it is stored under. Symlinks below the
+/// directory are skipped.
+fn collect_files(path: &Path, files: &mut Vec<(PathBuf, Option<String>)>) -> Result<()> {
+    if !path.exists() {
+        return Err(anyhow!("{} does not exist", path.display()));
+    }
+    if !path.is_dir() {
+        files.push((path.to_path_buf(), None));
+        return Ok(());
+    }
+
+    // Names start with the directory's own name, like paths in a tar archive
+    let root = fs::canonicalize(path)?;
+    let prefix = root.file_name().map(|name| name.to_string_lossy().to_string());
+
+    let walker = WalkDir::new(path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'));
+    for entry in walker {
+        let entry = entry?;
+        if entry.depth() > 0 && entry.path_is_symlink() {
+            eprintln!("Skipping symlink {}", entry.path().display());
+            continue;
+        }
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(path)?;
+        let name = prefix.iter().cloned()
+            .chain(relative.iter().map(|part| part.to_string_lossy().to_string()))
+            .collect::<Vec<_>>()
+            .join("_");
+        files.push((entry.into_path(), Some(name)));
+    }
+    Ok(())
+}
+
+/// Expands a glob pattern relative to its longest literal directory prefix.
+/// `*` does not cross directory separators; use `**` for that.
+fn glob_matches(pattern: &str) -> Result<Vec<PathBuf>> {
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| anyhow!("Invalid pattern {}: {}", pattern, e))?
+        .compile_matcher();
+
+    let components: Vec<&str> = pattern.split('/').collect();
+    let literal = components.iter()
+        .take_while(|c| !c.contains(['*', '?', '[', '{']))
+        .count();
+    let base = match components[..literal].join("/") {
+        base if base.is_empty() && pattern.starts_with('/') => "/".to_string(),
+        base if base.is_empty() => ".".to_string(),
+        base => base,
+    };
+
+    let mut walker = WalkDir::new(&base).min_depth(1).sort_by_file_name();
+    if !pattern.contains("**") {
+        walker = walker.max_depth(components.len() - literal);
+    }
+
+    // Like the shell, only match hidden entries if the pattern asks for them
+    let match_hidden = components.iter().any(|c| c.starts_with('.') && *c != "." && *c != "..");
+    let walker = walker.into_iter()
+        .filter_entry(|e| match_hidden || !e.file_name().to_string_lossy().starts_with('.'));
+
+    let mut matches = Vec::new();
+    for entry in walker {
+        let entry = entry?;
+        // Walking "." yields "./name", which a pattern like "*.pdf" would not match
+        let path = entry.path().strip_prefix(".").unwrap_or(entry.path());
+        if matcher.is_match(path) {
+            matches.push(path.to_path_buf());
+        }
+    }
+    Ok(matches)
+}
+
+/// Adds one file, or stdin for `-`, and prints what was stored.
+fn add_file(store: &Store, file_path: &str, name: Option<&str>, dedupe: bool, print: bool) -> Result<Added> {
+    let outcome = if file_path == "-" {
+        store.add_reader(io::stdin().lock(), name.unwrap_or(file_path), dedupe)?
     } else {
-        let path = PathBuf::from(file_path);
-        path.file_name()
+        store.add(Path::new(file_path), name, dedupe)?
+    };
+
+    let checksum = outcome.file().checksum.as_deref().unwrap_or_default();
+    match &outcome {
+        AddOutcome::Duplicate(existing) if print => {
+            println!("BLAKE3: {}", checksum);
+            println!("Identical file already stored as {} with UUID: {}", existing.filename, existing.uuid);
+        }
+        AddOutcome::Added { file, reused } if print => {
+            if let Some(existing) = reused {
+                println!("Reusing stored copy of {} ({})", existing.filename, existing.uuid);
+            }
+            println!("BLAKE3: {}", checksum);
+            println!("Added file with UUID: {}", file.uuid);
+        }
+        _ => {}
+    }
+
+    let duplicate = matches!(outcome, AddOutcome::Duplicate(_));
+    Ok(Added { file: outcome.file().clone(), duplicate, key: None })
+}
+
+/// Adds one file, or stdin for `-`, as ciphertext and prints what was stored.
+/// With `Encryption::Encrypt` the data is encrypted on the way in, so that
+/// neither the key nor the plaintext is written anywhere; the key is returned
+/// and printed once.
+fn add_encrypted_file(
+    store: &Store,
+    file_path: &str,
+    name: Option<&str>,
+    encryption: Encryption,
+    print: bool,
+) -> Result<Added> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => Path::new(file_path).file_name()
             .ok_or_else(|| anyhow!("Invalid filename"))?
             .to_string_lossy()
-            .to_string()
+            .to_string(),
+    };
+    let input: Box<dyn io::Read> = if file_path == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(fs::File::open(file_path).map_err(|e| anyhow!("Failed to open {}: {}", file_path, e))?)
     };
 
-    let (final_path, checksum) = if file_path == "-" {
-        // Handle stdin input
-        handle_stdin_upload(&config.base_dir, &filename)?
+    let (file, key) = if encryption == Encryption::Encrypt {
+        let key = crypto::generate_key();
+        let mut ciphertext = tempfile::tempfile()?;
+        crypto::encrypt(&key, input, BufWriter::new(&mut ciphertext))?;
+        ciphertext.seek(SeekFrom::Start(0))?;
+        (store.add_encrypted(ciphertext, &name)?, Some(key))
     } else {
-        // Handle regular file
-        let path = PathBuf::from(file_path);
-        (path.clone(), calculate_file_hash(&path)?)
+        (store.add_encrypted(input, &name)?, None)
     };
 
-    let uuid = Uuid::new_v4().to_string();
-    let target_dir = PathBuf::from(&config.base_dir).join(&uuid);
-    let target_file = target_dir.join(&filename);
+    if print {
+        println!("BLAKE3: {}", file.checksum.as_deref().unwrap_or_default());
+        println!("Added encrypted file with UUID: {}", file.uuid);
+        if let Some(key) = &key {
+            println!("Key: {} (append to links as #{}; it is not stored and cannot be recovered)", key, key);
+        }
+    }
+    Ok(Added { file, duplicate: false, key })
+}
 
-    create_dir_all(&target_dir)?;
-    fs::copy(&final_path, &target_file)?;
+/// Shares a file with `recipient`, or with every member when it names a group
+/// (`@legal`). Each member gets their own link and, with `--generate-password`,
+/// their own passphrase; a prompted password applies to all of them. `key` is
+/// the key of an encrypted file that was just added, if known.
+pub fn share_file(
+    store: &Store,
+    recipient: &str,
+    file_spec: &str,
+    flags: &ShareFlags,
+    key: Option<&str>,
+) -> Result<()> {
+    let file = store.resolve(file_spec)?;
+    let recipients = store.expand_recipients(recipient)?;
+
+    if flags.max_downloads == Some(0) {
+        return Err(anyhow!("Download limit must be at least 1"));
+    }
+    let expires = flags.expires.as_deref().map(parse_expiry).transpose()?;
+    let mail_config = if flags.notify {
+        let mail_config = store.config().mail.as_ref()
+            .ok_or_else(|| anyhow!("--notify requires a [mail] section in the configuration"))?;
+        mail::validate(mail_config)?;
+        Some(mail_config)
+    } else {
+        None
+    };
+    if mail_config.is_some() && file.encrypted && key.is_none() {
+        return Err(anyhow!("--notify needs the key of an encrypted file, which is only known \
+            when it is added; use `slink add --encrypt --share RECIPIENT --notify`"));
+    }
+    let prompted_password = if flags.password { Some(prompt_share_password()?) } else { None };
+
+    let mut failed_notifications = 0;
+    for (i, recipient) in recipients.iter().enumerate() {
+        let password = if flags.generate_password {
+            Some(generate_password())
+        } else {
+            prompted_password.clone()
+        };
+        let options = ShareOptions {
+            expires,
+            max_downloads: flags.max_downloads,
+            password,
+            encrypt: flags.encrypt,
+        };
+
+        let share = store.share(&file.uuid, recipient, &options)?;
+        let url = match key {
+            Some(key) => format!("{}#{}", store.share_url(&file, &share.share_hash), key),
+            None => store.share_url(&file, &share.share_hash),
+        };
 
-    // If this was a temp file, clean it up
-    if final_path.to_string_lossy().contains("slink_temp_") {
-        fs::remove_file(&final_path)?;
+        if i > 0 {
+            println!();
+        }
+        println!("Shared {} with {}:", file.filename, recipient);
+        println!("{}", url);
+        if let Some(expires) = options.expires {
+            println!("Expires: {}", expires.format("%Y-%m-%d %H:%M:%S"));
+        }
+        if let Some(max_downloads) = options.max_downloads {
+            println!("Download limit: {}", max_downloads);
+        }
+        if flags.generate_password {
+            println!("Password: {}", options.password.as_deref().unwrap_or_default());
+        } else if flags.password {
+            println!("Password protected");
+        } else if file.encrypted && key.is_some() {
+            println!("Encrypted (the key is only in this link and cannot be recovered)");
+        } else if file.encrypted {
+            println!("Encrypted (the link needs the #key printed when the file was added)");
+        }
+
+        if let Some(mail_config) = mail_config {
+            let notification = ShareNotification {
+                recipient,
+                filename: &file.filename,
+                size: file.size.map(format_size),
+                url: &url,
+                expires: options.expires,
+                max_downloads: options.max_downloads,
+                password_protected: share.password_protected,
+            };
+            // The share stays in place; the link can still be sent by hand
+            match mail::send_share_notification(mail_config, &notification) {
+                Ok(()) => println!("Notified {}", recipient),
+                Err(e) => {
+                    eprintln!("Failed to notify {}: {}", recipient, e);
+                    failed_notifications += 1;
+                }
+            }
+        }
     }
 
-    set_permissions_recursive(
-        &target_dir,
-        0o750,
-        0o640,
-        &config.web_user,
-        &config.web_group,
-    )?;
+    if failed_notifications > 0 {
+        return Err(anyhow!("{} notification(s) could not be sent", failed_notifications));
+    }
+    Ok(())
+}
 
-    conn.execute(
-        "INSERT INTO files (uuid, filename, date_added) VALUES (?1, ?2, ?3)",
-        params![uuid, filename, Utc::now()],
-    )?;
+/// Downloads an encrypted share and decrypts it with the key from the link
+/// fragment. Nothing is written under the final name unless decryption succeeds.
+pub fn fetch(link: &str, output: Option<&str>) -> Result<()> {
+    let (url, key) = link.split_once('#')
+        .ok_or_else(|| anyhow!("The link has no #key; only encrypted shares can be fetched"))?;
+
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => {
+            let name = url.rsplit('/').next().unwrap_or_default();
+            let name = percent_decode_str(name).decode_utf8()?;
+            // The name comes from the link and must not point outside the current directory
+            Path::new(name.as_ref()).file_name()
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow!("Cannot tell the file name from the link, use -o"))?
+        }
+    };
+    if output.exists() {
+        return Err(anyhow!("{} already exists", output.display()));
+    }
+
+    let response = ureq::get(url).call().map_err(|e| match e {
+        ureq::Error::Status(status, _) => anyhow!("Download failed: HTTP {}", status),
+        e => anyhow!("Download failed: {}", e),
+    })?;
 
-    println!("BLAKE3: {}", checksum);
-    println!("Added file with UUID: {}", uuid);
+    let dir = output.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut temp = tempfile::Builder::new().prefix(".slink_fetch_").tempfile_in(dir)?;
+    let size = slink::crypto::decrypt(key, response.into_reader(), BufWriter::new(temp.as_file_mut()))?;
+    temp.persist(&output)?;
 
-    Ok(uuid)
+    println!("Saved {} ({})", output.display(), format_size(size));
+    Ok(())
 }
 
-fn sanitize_filename(name: &str) -> Result<String> {
-    let name = name.trim();
+fn prompt_share_password() -> Result<String> {
+    let password = rpassword::prompt_password("Share password: ")?;
+    if password.is_empty() {
+        return Err(anyhow!("Password must not be empty"));
+    }
+    if rpassword::prompt_password("Repeat password: ")? != password {
+        return Err(anyhow!("Passwords do not match"));
+    }
+    Ok(password)
+}
+
+pub fn expire_shares(store: &Store) -> Result<()> {
+    let expired = store.expire_overdue()?;
 
-    // Basic security checks
-    if name.is_empty() {
-        return Err(anyhow!("Empty filename not allowed"));
+    for (uuid, recipient) in &expired {
+        let filename = store.file(uuid)?
+            .map_or_else(|| uuid.clone(), |f| f.filename);
+        println!("Expired share for {} from {}", filename, recipient);
     }
+    println!("{} share(s) expired", expired.len());
 
-    if name.contains('/') || name.contains('\\') || name.contains("..") {
-        return Err(anyhow!("Invalid characters in filename"));
+    let retired = store.expire_retired_links()?;
+    if retired > 0 {
+        println!("{} link(s) from a previous hash secret removed", retired);
     }
+    Ok(())
+}
 
-    // Remove any leading dots to prevent hidden files
-    let name = name.trim_start_matches('.');
-    if name.is_empty() {
-        return Err(anyhow!("Invalid filename (hidden files not allowed)"));
+pub fn rotate_secret(store: &mut Store, config_path: &Path, grace: Option<&str>, force: bool) -> Result<()> {
+    let grace_until = grace.map(parse_expiry).transpose()?;
+
+    if !force {
+        match grace_until {
+            Some(until) => print!("Replace the hash secret? Current links stop working at {}. [y/N] ",
+                until.format("%Y-%m-%d %H:%M:%S")),
+            None => print!("Replace the hash secret? All current links stop working immediately. [y/N] "),
+        }
+        io::stdout().flush()?;
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        if !input.trim().eq_ignore_ascii_case("y") {
+            return Ok(());
+        }
     }
 
-    // Additional checks for problematic characters
-    if name.chars().any(|c| {
-        c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*')
-    }) {
-        return Err(anyhow!("Invalid characters in filename"));
+    let rotated = store.rotate_secret(grace_until, config_path)?;
+
+    for share in &rotated {
+        // The key of an encrypted share is not stored; the recipient keeps it
+        let note = if share.encrypted { " (append the #key from the previous link)" } else { "" };
+        match store.file(&share.uuid)? {
+            Some(file) => println!("{} for {}: {}{}", file.filename, share.recipient,
+                store.share_url(&file, &share.new_hash), note),
+            None => println!("{} for {}: {}{}", share.uuid, share.recipient, share.new_hash, note),
+        }
     }
+    println!("Hash secret replaced, {} active share(s) moved to new links", rotated.len());
+    if let Some(until) = grace_until {
+        println!("Old links remain valid until {}; run `slink expire` after that to remove them",
+            until.format("%Y-%m-%d %H:%M:%S"));
+    }
+    Ok(())
+}
+
+pub fn unshare_file(store: &Store, recipient: &str, file_spec: &str) -> Result<()> {
+    let file = store.resolve(file_spec)?;
 
-    Ok(name.to_string())
+    for recipient in store.expand_recipients(recipient)? {
+        store.unshare(&file.uuid, &recipient)?;
+        println!("Removed share for {} from {}", file_spec, recipient);
+    }
+    Ok(())
 }
 
-fn handle_stdin_upload(base_dir: &str, filename: &str) -> Result<(PathBuf, String)> {
-    // Create temp file with prefix
-    let temp_dir = PathBuf::from(base_dir);
-    let temp_file = NamedTempFile::new_in(&temp_dir)?
-        .into_temp_path();
-    let temp_path = temp_file.to_path_buf();
+pub fn group_add(store: &Store, group: &str, recipients: &[String]) -> Result<()> {
+    let added = store.add_group_members(group, recipients)?;
+    let group = store.group(group)?
+        .ok_or_else(|| anyhow!("Group not found"))?;
 
-    // Rename with our prefix and the actual filename
-    let new_name = temp_dir.join(format!("slink_temp_{}_{}", Uuid::new_v4(), filename));
-    fs::rename(&temp_path, &new_name)?;
+    println!("Added {} recipient(s) to @{}, now {} member(s)", added, group.name, group.members.len());
+    Ok(())
+}
 
-    let file = fs::OpenOptions::new()
-        .write(true)
-        .create(false)
-        .open(&new_name)?;
-    let mut writer = BufWriter::new(file);
+pub fn group_remove(store: &Store, group: &str, recipients: &[String]) -> Result<()> {
+    if store.group(group)?.is_none() {
+        return Err(anyhow!("Unknown recipient group: {}", group));
+    }
 
-    // Setup BLAKE3 hasher
-    let mut hasher = blake3::Hasher::new();
+    let removed = store.remove_group_members(group, recipients)?;
+    match store.group(group)? {
+        Some(group) => println!("Removed {} recipient(s) from @{}, now {} member(s)",
+            removed, group.name, group.members.len()),
+        None => println!("Removed group {}", group),
+    }
+    Ok(())
+}
 
-    // Read from stdin and write to file while updating hash
-    let mut stdin = BufReader::new(io::stdin());
-    let mut buffer = [0; 8192];
+/// Lists all groups, or the members of one group.
+pub fn group_list(store: &Store, group: Option<&str>, format: OutputFormat) -> Result<()> {
+    let groups = match group {
+        Some(name) => vec![store.group(name)?
+            .ok_or_else(|| anyhow!("Unknown recipient group: {}", name))?],
+        None => store.groups()?,
+    };
 
-    loop {
-        match stdin.read(&mut buffer) {
-            Ok(0) => break, // EOF
-            Ok(n) => {
-                writer.write_all(&buffer[..n])?;
-                hasher.update(&buffer[..n]);
+    match format {
+        OutputFormat::Json => print_json(&groups),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record(["group", "recipient", "owner"])?;
+            for group in &groups {
+                for recipient in &group.members {
+                    writer.write_record([&group.name, recipient, group.owner.as_deref().unwrap_or_default()])?;
+                }
             }
-            Err(e) => {
-                // Clean up temp file on error
-                let _ = fs::remove_file(&new_name);
-                return Err(anyhow!("Error reading from stdin: {}", e));
+            writer.flush()?;
+            Ok(())
+        }
+        OutputFormat::Table => {
+            let mut table = Table::new();
+            table.add_row(row!["Group", "Members", "Owner"]);
+            for group in groups {
+                table.add_row(row![
+                    format!("@{}", group.name),
+                    group.members.join("\n"),
+                    group.owner.as_deref().unwrap_or("-")
+                ]);
             }
+            table.printstd();
+            Ok(())
         }
     }
+}
 
-    writer.flush()?;
+pub fn request_upload(store: &Store, recipient: &str, name: Option<&str>, expires: Option<&str>) -> Result<()> {
+    let expires = expires.map(parse_expiry).transpose()?;
+    let request = store.request_upload(recipient, name, expires)?;
+    let url = store.upload_url(&request);
 
-    Ok((new_name, hasher.finalize().to_hex().to_string()))
+    println!("Upload link for {}:", request.recipient);
+    println!("{}", url);
+    if let Some(expires) = request.date_expires {
+        println!("Expires: {}", expires.format("%Y-%m-%d %H:%M:%S"));
+    }
+    println!("Files can be sent with `curl -T FILE {}` while `slink serve` is running", url);
+    Ok(())
 }
 
-fn calculate_file_hash(path: &Path) -> Result<String> {
-    let mut file = fs::File::open(path)?;
-    let mut hasher = blake3::Hasher::new();
-    let mut buffer = [0; 8192];
+pub fn close_upload_request(store: &Store, request_hash: &str) -> Result<()> {
+    let request = store.close_upload_request(request_hash)?;
+    println!("Closed upload request {} for {} ({} file(s) received)",
+        request.request_hash, request.recipient, request.uploads);
+    Ok(())
+}
 
-    loop {
-        match file.read(&mut buffer) {
-            Ok(0) => break,
-            Ok(n) => hasher.update(&buffer[..n]),
-            Err(e) => return Err(anyhow!("Error reading file for hash: {}", e)),
-        };
-    }
+pub fn list_upload_requests(store: &Store, all: bool, format: OutputFormat) -> Result<()> {
+    let requests = store.upload_requests(all)?;
+    let status = |request: &UploadRequest| match (request.active, request.is_open()) {
+        (_, true) => "Open",
+        (true, false) => "Expired",
+        (false, _) => "Closed",
+    };
 
-    Ok(hasher.finalize().to_hex().to_string())
+    match format {
+        OutputFormat::Json => print_json(&requests),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record([
+                "request_hash", "recipient", "name_hint", "owner", "date_created", "date_expires",
+                "status", "uploads", "url",
+            ])?;
+            for request in &requests {
+                writer.write_record([
+                    request.request_hash.clone(),
+                    request.recipient.clone(),
+                    request.name_hint.clone().unwrap_or_default(),
+                    request.owner.clone(),
+                    request.date_created.to_rfc3339(),
+                    csv_date(request.date_expires),
+                    status(request).to_string(),
+                    request.uploads.to_string(),
+                    store.upload_url(request),
+                ])?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+        OutputFormat::Table => {
+            let mut table = Table::new();
+            table.add_row(row!["Hash", "Recipient", "Name", "Created", "Expires", "Status", "Uploads"]);
+            for request in &requests {
+                table.add_row(row![
+                    request.request_hash,
+                    request.recipient,
+                    request.name_hint.as_deref().unwrap_or("-"),
+                    request.date_created.format("%Y-%m-%d %H:%M:%S"),
+                    request.date_expires.map_or("-".to_string(), |d| d.format("%Y-%m-%d %H:%M:%S").to_string()),
+                    status(request),
+                    request.uploads
+                ]);
+            }
+            table.printstd();
+            Ok(())
+        }
+    }
 }
 
-pub fn share_file(config: &Config, recipient: &str, file_spec: &str) -> Result<()> {
-    let conn = Connection::open(&config.db_path)?;
-    let uuid = resolve_file_spec(&conn, file_spec)?;
+pub fn token_create(store: &Store, name: &str, user: Option<&str>) -> Result<()> {
+    let (token, secret) = store.create_token(name, user)?;
+    println!("Created API token {} for {}:", token.name, token.user);
+    println!("{}", secret);
+    println!("Store it now; it cannot be shown again.");
+    Ok(())
+}
 
-    let share_hash = ShareInfo::share(&conn, config, &uuid, recipient)?;
-    let file = FileShare::find_by_uuid(&conn, &uuid)?.ok_or_else(|| anyhow!("File not found"))?;
+pub fn token_list(store: &Store, format: OutputFormat) -> Result<()> {
+    let tokens = store.tokens()?;
+
+    match format {
+        OutputFormat::Json => print_json(&tokens),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record(["name", "user", "date_created", "date_last_used"])?;
+            for token in &tokens {
+                writer.write_record([
+                    token.name.clone(),
+                    token.user.clone(),
+                    token.date_created.to_rfc3339(),
+                    csv_date(token.date_last_used),
+                ])?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+        OutputFormat::Table => {
+            let mut table = Table::new();
+            table.add_row(row!["Name", "User", "Created", "Last used"]);
+            for token in tokens {
+                table.add_row(row![
+                    token.name,
+                    token.user,
+                    token.date_created.format("%Y-%m-%d %H:%M:%S"),
+                    token.date_last_used.map_or("-".to_string(), |d| d.format("%Y-%m-%d %H:%M:%S").to_string())
+                ]);
+            }
+            table.printstd();
+            Ok(())
+        }
+    }
+}
 
-    println!("Shared {} with {}:", file.filename, recipient);
-    println!("{}/{}/{}", config.base_url, share_hash, file.filename);
+pub fn token_remove(store: &Store, name: &str) -> Result<()> {
+    let token = store.revoke_token(name)?;
+    println!("Revoked API token {} of {}", token.name, token.user);
     Ok(())
 }
 
-pub fn unshare_file(config: &Config, recipient: &str, file_spec: &str) -> Result<()> {
-    let conn = Connection::open(&config.db_path)?;
-    let uuid = resolve_file_spec(&conn, file_spec)?;
+/// Lists the audit log, optionally only the entries about one file or link.
+pub fn audit_list(store: &Store, file: Option<&str>, hash: Option<&str>, format: OutputFormat) -> Result<()> {
+    // Removed files can only be named by UUID
+    let uuid = match file {
+        Some(file) if Uuid::parse_str(file).is_ok() => Some(file.to_string()),
+        Some(file) => Some(store.resolve(file)?.uuid),
+        None => None,
+    };
+    let entries = store.audit_log(uuid.as_deref(), hash)?;
+
+    match format {
+        OutputFormat::Json => print_json(&entries),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record([
+                "id", "timestamp", "user", "ssh_client", "command", "action", "uuid", "filename",
+                "recipient", "share_hash", "details", "prev_hash", "hash", "remote_addr",
+            ])?;
+            for entry in &entries {
+                writer.write_record([
+                    entry.id.to_string(),
+                    entry.timestamp.to_rfc3339(),
+                    entry.user.clone(),
+                    entry.ssh_client.clone().unwrap_or_default(),
+                    entry.command.clone(),
+                    entry.action.clone(),
+                    entry.uuid.clone().unwrap_or_default(),
+                    entry.filename.clone().unwrap_or_default(),
+                    entry.recipient.clone().unwrap_or_default(),
+                    entry.share_hash.clone().unwrap_or_default(),
+                    entry.details.clone().unwrap_or_default(),
+                    entry.prev_hash.clone(),
+                    entry.hash.clone(),
+                    entry.remote_addr.clone().unwrap_or_default(),
+                ])?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+        OutputFormat::Table => {
+            let mut table = Table::new();
+            table.add_row(row!["ID", "Time", "User", "From", "Action", "File", "Recipient", "Link", "Details"]);
+            for entry in entries {
+                let file = match (entry.filename, entry.uuid) {
+                    (Some(filename), Some(uuid)) => format!("{} ({})", filename, uuid),
+                    (filename, uuid) => filename.or(uuid).unwrap_or_else(|| "-".to_string()),
+                };
+                let from = match (entry.ssh_client, entry.remote_addr) {
+                    (Some(ssh_client), _) => ssh_client,
+                    (None, Some(remote_addr)) => format!("{} (http)", remote_addr),
+                    (None, None) => "-".to_string(),
+                };
+                table.add_row(row![
+                    entry.id,
+                    entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                    entry.user,
+                    from,
+                    entry.action,
+                    file,
+                    entry.recipient.as_deref().unwrap_or("-"),
+                    entry.share_hash.as_deref().unwrap_or("-"),
+                    entry.details.as_deref().unwrap_or("-")
+                ]);
+            }
+            table.printstd();
+            Ok(())
+        }
+    }
+}
+
+pub fn audit_verify(store: &Store, format: OutputFormat) -> Result<()> {
+    let verification = store.verify_audit_log()?;
 
-    ShareInfo::unshare(&conn, config, &uuid, recipient)?;
-    println!("Removed share for {} from {}", file_spec, recipient);
+    if format == OutputFormat::Json {
+        print_json(&verification)?;
+    } else {
+        for problem in &verification.problems {
+            println!("{}", problem);
+        }
+        if verification.problems.is_empty() {
+            println!("Audit log intact: {} entries", verification.entries);
+        }
+        if let Some(head) = &verification.head {
+            println!("Head: {}", head);
+        }
+    }
+
+    if !verification.problems.is_empty() {
+        return Err(anyhow!("Audit log has been tampered with: {} problem(s) found",
+            verification.problems.len()));
+    }
     Ok(())
 }
 
-pub fn show_file(config: &Config, file_spec: &str) -> Result<()> {
-    let conn = Connection::open(&config.db_path)?;
-    let uuid = resolve_file_spec(&conn, file_spec)?;
+pub fn show_file(store: &Store, file_spec: &str, format: OutputFormat) -> Result<()> {
+    let file = store.resolve(file_spec)?;
+    let shares = store.shares(&file.uuid)?;
+    let shares: Vec<ShareReport> = shares.iter().map(|share| ShareReport {
+        url: store.share_url(&file, &share.share_hash),
+        share,
+    }).collect();
+
+    match format {
+        OutputFormat::Json => print_json(&FileReport { file: &file, shares }),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record([
+                "uuid", "filename", "date_added", "checksum", "size", "mime_type", "owner",
+                "recipient", "share_hash", "url", "active",
+                "date_shared", "date_removed", "date_expires", "max_downloads", "password_protected",
+                "encrypted", "shared_by", "downloads", "last_accessed",
+            ])?;
+            for ShareReport { share, url } in &shares {
+                writer.write_record([
+                    file.uuid.clone(),
+                    file.filename.clone(),
+                    file.date_added.to_rfc3339(),
+                    file.checksum.clone().unwrap_or_default(),
+                    file.size.map_or(String::new(), |s| s.to_string()),
+                    file.mime_type.clone().unwrap_or_default(),
+                    file.owner.clone().unwrap_or_default(),
+                    share.recipient.clone(),
+                    share.share_hash.clone(),
+                    url.clone(),
+                    share.active.to_string(),
+                    share.date_shared.to_rfc3339(),
+                    csv_date(share.date_removed),
+                    csv_date(share.date_expires),
+                    share.max_downloads.map_or(String::new(), |m| m.to_string()),
+                    share.password_protected.to_string(),
+                    share.encrypted.to_string(),
+                    share.owner.clone().unwrap_or_default(),
+                    share.downloads.to_string(),
+                    csv_date(share.last_accessed),
+                ])?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+        OutputFormat::Table => {
+            println!("File: {}", file.filename);
+            println!("UUID: {}", file.uuid);
+            println!("Added: {}", file.date_added.format("%Y-%m-%d %H:%M:%S"));
+            println!("Size: {}", file.size.map_or("-".to_string(), format_size));
+            println!("Type: {}", file.mime_type.as_deref().unwrap_or("-"));
+            println!("BLAKE3: {}", file.checksum.as_deref().unwrap_or("-"));
+            println!("Owner: {}", file.owner.as_deref().unwrap_or("-"));
+            if file.encrypted {
+                println!("Encrypted: yes (stored as ciphertext, links need the #key)");
+            }
+            println!("\nShares:");
+
+            let mut table = Table::new();
+            table.add_row(row![
+                "Recipient", "Status", "Shared", "Removed", "Expires", "Downloads", "Last accessed", "URL"
+            ]);
+
+            for ShareReport { share, url } in shares {
+                let status = match (share.active, share.password_protected, share.encrypted) {
+                    (true, true, _) => "Active (password)",
+                    (true, false, true) => "Active (encrypted)",
+                    (true, false, false) => "Active",
+                    (false, _, _) => "Removed",
+                };
+                let removed = share.date_removed.map_or("-".to_string(), 
+                    |d| d.format("%Y-%m-%d %H:%M:%S").to_string());
+                let expires = share.date_expires.map_or("-".to_string(),
+                    |d| d.format("%Y-%m-%d %H:%M:%S").to_string());
+                let downloads = share.max_downloads.map_or(share.downloads.to_string(),
+                    |max| format!("{}/{}", share.downloads, max));
+                let last_accessed = share.last_accessed.map_or("-".to_string(),
+                    |d| d.format("%Y-%m-%d %H:%M:%S").to_string());
+
+                table.add_row(row![
+                    share.recipient,
+                    status,
+                    share.date_shared.format("%Y-%m-%d %H:%M:%S"),
+                    removed,
+                    expires,
+                    downloads,
+                    last_accessed,
+                    url
+                ]);
+            }
 
-    let file = FileShare::find_by_uuid(&conn, &uuid)?.ok_or_else(|| anyhow!("File not found"))?;
-    let shares = ShareInfo::get_shares(&conn, &uuid)?;
+            table.printstd();
+            Ok(())
+        }
+    }
+}
 
-    println!("File: {}", file.filename);
-    println!("UUID: {}", file.uuid);
-    println!("Added: {}", file.date_added.format("%Y-%m-%d %H:%M:%S"));
-    println!("\nShares:");
+pub fn list_files(store: &Store, filter: &ListFilter, format: OutputFormat) -> Result<()> {
+    let rows = store.list(filter)?;
+
+    match format {
+        OutputFormat::Json => print_json(&rows),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record([
+                "uuid", "filename", "date_added", "checksum", "size", "mime_type", "owner", "active_shares"
+            ])?;
+            for FileListing { file, active_shares } in &rows {
+                writer.write_record([
+                    file.uuid.clone(),
+                    file.filename.clone(),
+                    file.date_added.to_rfc3339(),
+                    file.checksum.clone().unwrap_or_default(),
+                    file.size.map_or(String::new(), |s| s.to_string()),
+                    file.mime_type.clone().unwrap_or_default(),
+                    file.owner.clone().unwrap_or_default(),
+                    active_shares.to_string(),
+                ])?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+        OutputFormat::Table => {
+            let mut table = Table::new();
+            table.add_row(row!["Filename", "UUID", "Added", "Size", "Type", "Owner", "Active Shares"]);
+
+            for FileListing { file, active_shares } in rows {
+                table.add_row(row![
+                    file.filename,
+                    file.uuid,
+                    file.date_added.format("%Y-%m-%d %H:%M:%S"),
+                    file.size.map_or("-".to_string(), format_size),
+                    file.mime_type.as_deref().unwrap_or("-"),
+                    file.owner.as_deref().unwrap_or("-"),
+                    active_shares
+                ]);
+            }
 
-    let mut table = Table::new();
-    table.add_row(row!["Recipient", "Status", "Shared", "Removed", "URL"]);
+            table.printstd();
+            Ok(())
+        }
+    }
+}
 
-    for share in shares {
-        let status = if share.active { "Active" } else { "Removed" };
-        let removed = share.date_removed.map_or("-".to_string(), 
-            |d| d.format("%Y-%m-%d %H:%M:%S").to_string());
-        let url = format!("{}/{}/{}", config.base_url, share.share_hash, file.filename);
+pub fn verify_files(store: &Store, file_spec: Option<&str>) -> Result<()> {
+    let files = match file_spec {
+        Some(file_spec) => vec![store.resolve(file_spec)?],
+        None => store.files()?,
+    };
 
-        table.add_row(row![
-            share.recipient,
-            status,
-            share.date_shared.format("%Y-%m-%d %H:%M:%S"),
-            removed,
-            url
-        ]);
+    let mut failures = 0;
+    for file in &files {
+        let status = match store.verify(file)? {
+            Verification::Ok => "OK".to_string(),
+            Verification::NoChecksum => "UNKNOWN (no checksum recorded)".to_string(),
+            Verification::Missing => "MISSING".to_string(),
+            Verification::ChecksumMismatch { actual } => format!("MISMATCH (BLAKE3 {})", actual),
+            Verification::SizeMismatch { actual } => format!("MISMATCH (size {})", actual),
+        };
+        if status.starts_with("MISSING") || status.starts_with("MISMATCH") {
+            failures += 1;
+        }
+        println!("{} {} ({}/{}/{}): {}", file.uuid, file.filename,
+            store.storage().location(), file.uuid, file.filename, status);
     }
 
-    table.printstd();
+    if failures > 0 {
+        return Err(anyhow!("{} of {} file(s) failed verification", failures, files.len()));
+    }
+    println!("{} file(s) verified", files.len());
     Ok(())
 }
 
-pub fn list_files(config: &Config) -> Result<()> {
-    let conn = Connection::open(&config.db_path)?;
-    let mut stmt = conn.prepare(
-        "SELECT f.uuid, f.filename, f.date_added, COUNT(s.uuid) as share_count 
-         FROM files f 
-         LEFT JOIN shares s ON f.uuid = s.uuid AND s.active = 1
-         GROUP BY f.uuid 
-         ORDER BY f.date_added DESC"
-    )?;
-
-    let mut table = Table::new();
-    table.add_row(row!["Filename", "UUID", "Added", "Active Shares"]);
-
-    let rows = stmt.query_map([], |row| {
-        Ok((
-            row.get::<_, String>(0)?,
-            row.get::<_, String>(1)?,
-            row.get::<_, DateTime<Utc>>(2)?,
-            row.get::<_, i64>(3)?
-        ))
+pub fn ingest_log(store: &Store, path: &str) -> Result<()> {
+    let report = store.ingest_log(path, |filename, recipient| {
+        println!("Download limit reached, removed share for {} from {}", filename, recipient);
     })?;
+    println!("Processed {} lines: {} downloads recorded, {} other requests ignored, {} lines unparseable",
+        report.lines, report.recorded, report.ignored, report.unparseable);
+    Ok(())
+}
 
-    for row in rows {
-        let (uuid, filename, date_added, share_count) = row?;
-        table.add_row(row![
-            filename,
-            uuid,
-            date_added.format("%Y-%m-%d %H:%M:%S"),
-            share_count
-        ]);
+pub fn fsck(store: &Store, repair: bool) -> Result<()> {
+    let report = store.fsck(repair)?;
+    for problem in &report.problems {
+        println!("{}", problem.description);
+    }
+    if report.problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+    if !repair {
+        return Err(anyhow!("{} problem(s) found, run `slink fsck --repair` to fix them",
+            report.problems.len()));
     }
 
-    table.printstd();
+    let mut unrepaired = 0;
+    for (problem, outcome) in report.problems.iter().zip(&report.repairs) {
+        match outcome {
+            RepairOutcome::Repaired(action) => println!("Repaired: {}", action),
+            RepairOutcome::Skipped(reason) => {
+                println!("Skipped: {}", reason);
+                unrepaired += 1;
+            }
+            RepairOutcome::Failed(e) => {
+                eprintln!("Failed to repair \"{}\": {}", problem.description, e);
+                unrepaired += 1;
+            }
+        }
+    }
+    if unrepaired > 0 {
+        return Err(anyhow!("{} problem(s) could not be repaired", unrepaired));
+    }
     Ok(())
 }
 
-pub fn remove_file(config: &Config, file_spec: &str, force: bool) -> Result<()> {
+pub fn db_migrate(config: &Config) -> Result<()> {
+    let mut conn = Connection::open(&config.db_path)?;
+    let from = migrations::current_version(&conn)?;
+    let applied = migrations::migrate(&mut conn)?;
+
+    if applied.is_empty() {
+        println!("Database is up to date (version {})", from);
+        return Ok(());
+    }
+    for migration in &applied {
+        println!("Applied migration {}: {}", migration.version, migration.description);
+    }
+    let to = migrations::current_version(&conn)?;
+    let mut details = format!("schema version {} to {}", from, to);
+
+    if applied.iter().any(|migration| migration.version == 14) {
+        let removed = migrations::remove_encrypted_copies(&conn, storage::open(config)?.as_ref())?;
+        if removed > 0 {
+            println!("Unpublished {} encrypted share(s) ended by migration 14", removed);
+            details += &format!(", unpublished {} encrypted share(s)", removed);
+        }
+    }
+    audit::record(&conn, &Actor::current(), Event {
+        action: "db-migrate",
+        details: Some(details),
+        ..Event::default()
+    })?;
+    println!("Database migrated from version {} to {}", from, to);
+    Ok(())
+}
+
+pub fn db_status(config: &Config) -> Result<()> {
     let conn = Connection::open(&config.db_path)?;
-    let uuid = resolve_file_spec(&conn, file_spec)?;
+    let current = migrations::current_version(&conn)?;
+
+    println!("Database: {}", config.db_path);
+    println!("Schema version: {} (latest: {})", current, migrations::latest_version());
 
-    if let Some(file) = FileShare::find_by_uuid(&conn, &uuid)? {
-        file.remove(&conn, config, force)?;
-        println!("Removed file: {}", file.filename);
+    let mut table = Table::new();
+    table.add_row(row!["Version", "Description", "Status"]);
+    for migration in migrations::MIGRATIONS {
+        let status = if migration.version <= current { "Applied" } else { "Pending" };
+        table.add_row(row![migration.version, migration.description, status]);
     }
+    table.printstd();
+    Ok(())
+}
+
+pub fn change_owner(store: &Store, owner: &str, file_spec: &str) -> Result<()> {
+    let file = store.set_owner(&store.resolve(file_spec)?.uuid, owner)?;
+    println!("{} ({}) now belongs to {}", file.filename, file.uuid, owner);
     Ok(())
 }
 
-fn resolve_file_spec(conn: &Connection, file_spec: &str) -> Result<String> {
-    // If input looks like a UUID, use it directly
-    if file_spec.len() == 36 && file_spec.chars().filter(|c| *c == '-').count() == 4 {
-        return Ok(file_spec.to_string());
+pub fn remove_file(store: &Store, file_spec: &str, force: bool) -> Result<()> {
+    let file = store.resolve(file_spec)?;
+
+    if !force {
+        print!("Are you sure you want to remove {}? [y/N] ", file.filename);
+        io::stdout().flush()?;
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        if !input.trim().eq_ignore_ascii_case("y") {
+            return Ok(());
+        }
     }
 
-    // Split filename and optional index
-    let parts: Vec<&str> = file_spec.split('/').collect();
-    let (filename, index) = match parts.as_slice() {
-        [filename] => (filename, 1),
-        [filename, index_str] => (filename, index_str.parse::<usize>()
-            .map_err(|_| anyhow!("Invalid index format"))?),
-        _ => return Err(anyhow!("Invalid file specification")),
+    store.remove(&file.uuid)?;
+    println!("Removed file: {}", file.filename);
+    Ok(())
+}
+
+pub fn show_info(store: &Store, config_path: &Path, format: OutputFormat) -> Result<()> {
+    let config = store.config();
+
+    let configuration = config_path.exists().then(|| InfoConfig {
+        profile: config.profile.as_deref(),
+        base_url: &config.base_url,
+        base_dir: &config.base_dir,
+        db_path: &config.db_path,
+        web_user: &config.web_user,
+        web_group: &config.web_group,
+        hash_bytes: config.hash_bytes,
+        admin_group: config.admin_group.as_deref(),
+    });
+
+    // Database statistics
+    let statistics = if Path::new(&config.db_path).exists() {
+        Some(store.statistics()?)
+    } else {
+        None
     };
 
-    let matches = FileShare::find_by_name(conn, filename)?;
+    let info = Info {
+        version: env!("CARGO_PKG_VERSION"),
+        config_file: config_path.to_string_lossy().to_string(),
+        configuration,
+        statistics,
+    };
 
-    if matches.is_empty() {
-        return Err(anyhow!("File not found: {}", filename));
-    }
+    match format {
+        OutputFormat::Json => print_json(&info),
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout());
+            writer.write_record(["key", "value"])?;
+            writer.write_record(["version", info.version])?;
+            writer.write_record(["config_file", &info.config_file])?;
+            if let Some(c) = &info.configuration {
+                writer.write_record(["profile", c.profile.unwrap_or_default()])?;
+                writer.write_record(["base_url", c.base_url])?;
+                writer.write_record(["base_dir", c.base_dir])?;
+                writer.write_record(["db_path", c.db_path])?;
+                writer.write_record(["web_user", c.web_user])?;
+                writer.write_record(["web_group", c.web_group])?;
+                writer.write_record(["hash_bytes", &c.hash_bytes.to_string()])?;
+                writer.write_record(["admin_group", c.admin_group.unwrap_or_default()])?;
+            }
+            if let Some(st) = &info.statistics {
+                writer.write_record(["file_count", &st.file_count.to_string()])?;
+                writer.write_record(["total_shares", &st.total_shares.to_string()])?;
+                writer.write_record(["active_shares", &st.active_shares.to_string()])?;
+                writer.write_record(["oldest_file", &csv_date(st.oldest_file)])?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+        OutputFormat::Table => {
+            println!("slink v{}", info.version);
+            println!("\nConfiguration:");
+            println!("Config file: {}", info.config_file);
+
+            if info.configuration.is_some() {
+                println!("\nCurrent configuration:");
+                if let Some(profile) = &config.profile {
+                    println!("Profile: {}", profile);
+                }
+                println!("Base URL: {}", config.base_url);
+                println!("Base directory: {}", config.base_dir);
+                println!("Database path: {}", config.db_path);
+                println!("Hash secret: [REDACTED] ({} characters)", config.hash_secret.chars().count());
+                println!("Web user: {}", config.web_user);
+                println!("Web group: {}", config.web_group);
+                println!("Hash bytes: {} ({} bits of entropy)", config.hash_bytes, config.hash_bytes*8);
+                match &config.admin_group {
+                    Some(group) => println!("Admin group: {} ({} is {}an admin)", group,
+                        store.actor().user, if store.is_admin() { "" } else { "not " }),
+                    None => println!("Admin group: none (single-user mode)"),
+                }
+            } else {
+                println!("\nNo configuration file found. Default configuration will be created on first use.");
+            }
 
-    if matches.len() > 1 && parts.len() == 1 {
-        println!("Multiple files found:");
-        for (i, (uuid, date)) in matches.iter().enumerate() {
-            println!("{}/{}: {} ({})", filename, i + 1, uuid, 
-                    date.format("%Y-%m-%d %H:%M:%S"));
+            if let Some(st) = info.statistics {
+                println!("\nDatabase statistics:");
+                println!("Total files: {}", st.file_count);
+                println!("Total shares: {}", st.total_shares);
+                println!("Active shares: {}", st.active_shares);
+                println!("Oldest file: {}", st.oldest_file.map_or("No files".to_string(),
+                    |dt| dt.format("%Y-%m-%d %H:%M:%S").to_string()));
+            } else {
+                println!("\nDatabase not initialized yet.");
+            }
+            Ok(())
         }
-        return Err(anyhow!("Please specify file index"));
     }
-
-    matches.get(index - 1)
-        .map(|(uuid, _)| uuid.clone())
-        .ok_or_else(|| anyhow!("Invalid file index"))
 }
 
-pub fn show_info(config: &Config) -> Result<()> {
-    println!("slink v{}", env!("CARGO_PKG_VERSION"));
-    println!("\nConfiguration:");
+/// `show` output: the file and every share with its public URL.
+#[derive(Serialize)]
+struct FileReport<'a> {
+    #[serde(flatten)]
+    file: &'a FileShare,
+    shares: Vec<ShareReport<'a>>,
+}
 
-    let config_path = config_dir()
-        .ok_or_else(|| anyhow!("Could not determine config directory"))?
-        .join("slink")
-        .join("slink.conf");
+#[derive(Serialize)]
+struct ShareReport<'a> {
+    #[serde(flatten)]
+    share: &'a ShareInfo,
+    url: String,
+}
 
-    println!("Config file: {}", config_path.display());
+/// `info` output. The hash secret is deliberately left out.
+#[derive(Serialize)]
+struct Info<'a> {
+    version: &'a str,
+    config_file: String,
+    configuration: Option<InfoConfig<'a>>,
+    statistics: Option<Statistics>,
+}
 
-    if config_path.exists() {
-        println!("\nCurrent configuration:");
-        println!("Base URL: {}", config.base_url);
-        println!("Base directory: {}", config.base_dir);
-        println!("Database path: {}", config.db_path);
-        println!("Hash secret: {}..[REDACTED]..{}", 
-            &config.hash_secret[..2],
-            &config.hash_secret[config.hash_secret.len()-2..]
-        );
-        println!("Web user: {}", config.web_user);
-        println!("Web group: {}", config.web_group);
-        println!("Hash bytes: {} ({} bits of entropy)", config.hash_bytes, config.hash_bytes*8);
-    } else {
-        println!("\nNo configuration file found. Default configuration will be created on first use.");
-    }
+#[derive(Serialize)]
+struct InfoConfig<'a> {
+    profile: Option<&'a str>,
+    base_url: &'a str,
+    base_dir: &'a str,
+    db_path: &'a str,
+    web_user: &'a str,
+    web_group: &'a str,
+    hash_bytes: usize,
+    admin_group: Option<&'a str>,
+}
 
-    // Database statistics
-    let db_path = Path::new(&config.db_path);
-    if db_path.exists() {
-        println!("\nDatabase statistics:");
-        let conn = Connection::open(&config.db_path)?;
-
-        let file_count: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM files",
-            [],
-            |row| row.get(0)
-        )?;
-
-        let total_shares: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM shares",
-            [],
-            |row| row.get(0)
-        )?;
-
-        let active_shares: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM shares WHERE active = 1",
-            [],
-            |row| row.get(0)
-        )?;
-
-        // Handle NULL case explicitly for oldest file
-        let oldest_file: String = if file_count > 0 {
-            conn.query_row(
-                "SELECT date_added FROM files ORDER BY date_added ASC LIMIT 1",
-                [],
-                |row| row.get::<_, DateTime<Utc>>(0)
-            ).map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())?
-        } else {
-            "No files".to_string()
-        };
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
 
-        println!("Total files: {}", file_count);
-        println!("Total shares: {}", total_shares);
-        println!("Active shares: {}", active_shares);
-        println!("Oldest file: {}", oldest_file);
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
     } else {
-        println!("\nDatabase not initialized yet.");
+        format!("{:.1} {}", size, UNITS[unit])
     }
-
-    Ok(())
 }
 
+fn csv_date(date: Option<DateTime<Utc>>) -> String {
+    date.map_or(String::new(), |d| d.to_rfc3339())
+}
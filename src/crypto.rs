@@ -0,0 +1,220 @@
+// src/crypto.rs
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64, Engine as _};
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit};
+use std::io::{Read, Write};
+
+/*
+Encrypted share format:
+- Header: MAGIC (8 bytes), format version (1 byte), STREAM nonce prefix (7 bytes)
+- Body: the file in CHUNK_SIZE plaintext chunks, each sealed with ChaCha20-Poly1305
+  in the STREAM construction (big-endian 32-bit counter and last-chunk flag), so
+  truncation and reordering are detected
+- The final chunk is always shorter than CHUNK_SIZE, and empty if the file size
+  is a multiple of it
+- The 32-byte key is only ever shown as the URL fragment (base64url), which
+  browsers and HTTP clients do not send to the server
+*/
+
+const MAGIC: &[u8; 8] = b"slinkenc";
+const VERSION: u8 = 1;
+const NONCE_PREFIX_LEN: usize = 7;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// Returns a new random key, encoded as it appears in the URL fragment.
+pub fn generate_key() -> String {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    b64.encode(key)
+}
+
+fn decode_key(key: &str) -> Result<Key> {
+    let bytes = b64.decode(key.trim())
+        .map_err(|_| anyhow!("Invalid decryption key"))?;
+    if bytes.len() != 32 {
+        return Err(anyhow!("Invalid decryption key"));
+    }
+    Ok(*Key::from_slice(&bytes))
+}
+
+/// Encrypts everything read from `reader` into `writer` under `key`.
+pub fn encrypt<R: Read, W: Write>(key: &str, mut reader: R, mut writer: W) -> Result<()> {
+    let cipher = ChaCha20Poly1305::new(&decode_key(key)?);
+    let mut nonce = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&nonce)?;
+
+    let mut encryptor = EncryptorBE32::from_aead(cipher, nonce.as_slice().into());
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = read_full(&mut reader, &mut buffer)?;
+        if n < CHUNK_SIZE {
+            let chunk = encryptor.encrypt_last(&buffer[..n])
+                .map_err(|_| anyhow!("Encryption failed"))?;
+            writer.write_all(&chunk)?;
+            break;
+        }
+        let chunk = encryptor.encrypt_next(&buffer[..n])
+            .map_err(|_| anyhow!("Encryption failed"))?;
+        writer.write_all(&chunk)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decrypts data produced by `encrypt` and returns the plaintext size. Fails
+/// on a wrong key and on any modified or truncated input; plaintext already
+/// written to `writer` must then be discarded.
+pub fn decrypt<R: Read, W: Write>(key: &str, mut reader: R, mut writer: W) -> Result<u64> {
+    let cipher = ChaCha20Poly1305::new(&decode_key(key)?);
+
+    let mut header = [0u8; 16];
+    if read_full(&mut reader, &mut header)? < header.len() || &header[..8] != MAGIC {
+        return Err(anyhow!("Not an encrypted slink share"));
+    }
+    if header[8] != VERSION {
+        return Err(anyhow!("Unsupported encryption format version {}", header[8]));
+    }
+
+    let mut decryptor = DecryptorBE32::from_aead(cipher, header[9..].into());
+    let mut buffer = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+    let mut size = 0;
+    loop {
+        let n = read_full(&mut reader, &mut buffer)?;
+        if n < CHUNK_SIZE + TAG_SIZE {
+            let chunk = decryptor.decrypt_last(&buffer[..n])
+                .map_err(|_| anyhow!("Decryption failed: wrong key or damaged download"))?;
+            writer.write_all(&chunk)?;
+            size += chunk.len() as u64;
+            break;
+        }
+        let chunk = decryptor.decrypt_next(&buffer[..n])
+            .map_err(|_| anyhow!("Decryption failed: wrong key or damaged download"))?;
+        writer.write_all(&chunk)?;
+        size += chunk.len() as u64;
+    }
+    writer.flush()?;
+    Ok(size)
+}
+
+/// Fails unless `reader` starts with the header `encrypt` writes, so that
+/// data uploaded as already encrypted can be told apart from plaintext.
+pub fn check_header<R: Read>(mut reader: R) -> Result<()> {
+    let mut header = [0u8; 16];
+    if read_full(&mut reader, &mut header)? < header.len() || &header[..8] != MAGIC {
+        return Err(invalid!("Not encrypted with `slink add --encrypt`"));
+    }
+    if header[8] != VERSION {
+        return Err(invalid!("Unsupported encryption format version {}", header[8]));
+    }
+    Ok(())
+}
+
+/// Reads until `buffer` is full or the input ends; returns the bytes read.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt_to_vec(key: &str, plaintext: &[u8]) -> Vec<u8> {
+        let mut ciphertext = Vec::new();
+        encrypt(key, plaintext, &mut ciphertext).unwrap();
+        ciphertext
+    }
+
+    /// The plaintext, or the error; what was written before a failure is dropped.
+    fn decrypt_to_vec(key: &str, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        let size = decrypt(key, ciphertext, &mut plaintext)?;
+        assert_eq!(size, plaintext.len() as u64);
+        Ok(plaintext)
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_around_the_chunk_size() {
+        let key = generate_key();
+        for len in [1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 2 * CHUNK_SIZE, 2 * CHUNK_SIZE + 5] {
+            let plaintext = data(len);
+            let ciphertext = encrypt_to_vec(&key, &plaintext);
+
+            // One tag per full chunk, plus the shorter (possibly empty) last chunk
+            let chunks = len / CHUNK_SIZE + 1;
+            assert_eq!(ciphertext.len(), 16 + len + chunks * TAG_SIZE, "length {}", len);
+            assert_eq!(decrypt_to_vec(&key, &ciphertext).unwrap(), plaintext, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn round_trips_empty_input() {
+        let key = generate_key();
+        let ciphertext = encrypt_to_vec(&key, b"");
+        assert_eq!(ciphertext.len(), 16 + TAG_SIZE);
+        assert_eq!(decrypt_to_vec(&key, &ciphertext).unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_a_truncated_final_chunk() {
+        let key = generate_key();
+        for len in [0, 100, CHUNK_SIZE + 100, 2 * CHUNK_SIZE] {
+            let ciphertext = encrypt_to_vec(&key, &data(len));
+            assert!(decrypt_to_vec(&key, &ciphertext[..ciphertext.len() - 1]).is_err(), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_a_missing_final_chunk() {
+        let key = generate_key();
+        // The last chunk is only a tag when the size is a multiple of CHUNK_SIZE
+        let ciphertext = encrypt_to_vec(&key, &data(2 * CHUNK_SIZE));
+        assert!(decrypt_to_vec(&key, &ciphertext[..ciphertext.len() - TAG_SIZE]).is_err());
+
+        let ciphertext = encrypt_to_vec(&key, &data(CHUNK_SIZE + 100));
+        assert!(decrypt_to_vec(&key, &ciphertext[..16 + CHUNK_SIZE + TAG_SIZE]).is_err());
+    }
+
+    #[test]
+    fn rejects_a_changed_byte() {
+        let key = generate_key();
+        let ciphertext = encrypt_to_vec(&key, &data(CHUNK_SIZE + 100));
+        // In the nonce, the first chunk and the last chunk
+        for position in [10, 20, ciphertext.len() - 1] {
+            let mut changed = ciphertext.clone();
+            changed[position] ^= 1;
+            assert!(decrypt_to_vec(&key, &changed).is_err(), "byte {}", position);
+        }
+    }
+
+    #[test]
+    fn rejects_a_wrong_key() {
+        let ciphertext = encrypt_to_vec(&generate_key(), b"secret report");
+        assert!(decrypt_to_vec(&generate_key(), &ciphertext).is_err());
+        assert!(decrypt_to_vec("not a key", &ciphertext).is_err());
+    }
+
+    #[test]
+    fn checks_the_header() {
+        let ciphertext = encrypt_to_vec(&generate_key(), b"secret report");
+        assert!(check_header(&ciphertext[..]).is_ok());
+        assert!(check_header(&b"plain text that is long enough"[..]).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+// src/fsck.rs
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::os::unix::fs::{symlink as unix_symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+use crate::{remove_file_with_access, remove_share_link, set_permissions_recursive};
+use crate::audit::{self, Event};
+use crate::migrations;
+use crate::{Actor, Config, FileShare, Storage, StorageConfig};
+
+/// Temp files younger than this may belong to an upload still in progress
+const TEMP_FILE_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+enum Repair {
+    /// Drop the database row of a file whose data is gone, as `slink rm` would
+    RemoveFileRecord(FileShare),
+    /// Delete a stray file or directory in BASE_DIR
+    RemovePath(PathBuf),
+    RemoveLink(String),
+    CreateLink { share_hash: String, uuid: String },
+    FixPermissions(PathBuf),
+    /// Reported only; the reason is shown instead of repairing
+    Skip(&'static str),
+}
+
+/// An inconsistency between the database and BASE_DIR.
+pub struct Problem {
+    pub description: String,
+    repair: Repair,
+}
+
+pub enum RepairOutcome {
+    /// What was done
+    Repaired(String),
+    /// Why the problem was left alone
+    Skipped(&'static str),
+    /// The error that stopped the repair
+    Failed(String),
+}
+
+/// What `fsck` found and, when repairing, what became of each problem.
+pub struct FsckReport {
+    pub problems: Vec<Problem>,
+    /// One outcome per problem, in the same order; empty unless repairing
+    pub repairs: Vec<RepairOutcome>,
+}
+
+/// See `Store::fsck`, which checks that the actor may repair. Every repair is
+/// recorded in the audit log as `actor`.
+pub(crate) fn fsck(
+    conn: &Connection,
+    config: &Config,
+    storage: &dyn Storage,
+    actor: &Actor,
+    repair: bool,
+) -> Result<FsckReport> {
+    if !matches!(config.storage, None | Some(StorageConfig::Local)) {
+        return Err(anyhow!("fsck only checks the local storage backend"));
+    }
+
+    let base_dir = PathBuf::from(&config.base_dir);
+
+    let files: BTreeMap<String, FileShare> = FileShare::find_all(conn)?
+        .into_iter()
+        .map(|file| (file.uuid.clone(), file))
+        .collect();
+    let active_shares = active_share_links(conn, "password_hash IS NULL")?;
+    let protected_shares = active_share_links(conn, "password_hash IS NOT NULL")?;
+    let encrypted_copies = migrations::encrypted_copy_hashes(conn)?;
+    let retired_links = retired_share_links(conn)?;
+
+    let mut problems = Vec::new();
+    let mut uuid_dirs = BTreeSet::new();
+    let mut encrypted_dirs = BTreeSet::new();
+    let mut links = BTreeMap::new();
+
+    for entry in fs::read_dir(&base_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file_type = entry.file_type()?;
+
+        // Uploads are spooled outside BASE_DIR now, but earlier versions left them here
+        if name.starts_with("slink_temp_") {
+            let age = entry.metadata()?.modified()
+                .ok()
+                .and_then(|m| SystemTime::now().duration_since(m).ok())
+                .unwrap_or_default();
+            problems.push(Problem {
+                description: format!("Leftover upload temp file {}", entry.path().display()),
+                repair: if age >= TEMP_FILE_MIN_AGE {
+                    Repair::RemovePath(entry.path())
+                } else {
+                    Repair::Skip("modified within the last hour, upload may be in progress")
+                },
+            });
+        } else if file_type.is_symlink() {
+            links.insert(name, fs::read_link(entry.path())?);
+        } else if file_type.is_dir() && Uuid::parse_str(&name).is_ok() {
+            uuid_dirs.insert(name);
+        } else if file_type.is_dir() && encrypted_copies.contains(&name) {
+            encrypted_dirs.insert(name);
+        }
+    }
+
+    // Database rows whose data is missing
+    for file in files.values() {
+        if !file.path(config).exists() {
+            problems.push(Problem {
+                description: format!("File {} ({}) is missing from {}",
+                    file.filename, file.uuid, file.path(config).display()),
+                repair: Repair::RemoveFileRecord(file.clone()),
+            });
+        }
+    }
+
+    // Stored data without a database row
+    for uuid in &uuid_dirs {
+        if !files.contains_key(uuid) {
+            problems.push(Problem {
+                description: format!("Directory {} has no database entry", base_dir.join(uuid).display()),
+                repair: Repair::RemovePath(base_dir.join(uuid)),
+            });
+        }
+    }
+
+    // Links in BASE_DIR
+    for (share_hash, target) in &links {
+        let target_uuid = target.to_string_lossy().to_string();
+        let description = if !base_dir.join(target).exists() {
+            Some(format!("Link {} -> {} is dangling", share_hash, target.display()))
+        } else if protected_shares.contains_key(share_hash) {
+            Some(format!("Link {} bypasses the password of its share", share_hash))
+        } else if !files.contains_key(&target_uuid) {
+            Some(format!("Link {} -> {} points to a removed file", share_hash, target.display()))
+        } else if active_shares.get(share_hash) != Some(&target_uuid)
+            && retired_links.get(share_hash) != Some(&target_uuid)
+        {
+            Some(format!("Link {} -> {} does not belong to an active share", share_hash, target.display()))
+        } else {
+            None
+        };
+
+        if let Some(description) = description {
+            problems.push(Problem { description, repair: Repair::RemoveLink(share_hash.clone()) });
+        }
+    }
+
+    // Active shares without a working link
+    for (share_hash, uuid) in &active_shares {
+        let has_link = links.get(share_hash).is_some_and(|target| target == Path::new(uuid));
+        if files.contains_key(uuid) && !has_link {
+            problems.push(Problem {
+                description: format!("Active share {} for {} has no link", share_hash, uuid),
+                repair: Repair::CreateLink { share_hash: share_hash.clone(), uuid: uuid.clone() },
+            });
+        }
+    }
+
+    // Per-share copies published as BASE_DIR/HASH/filename by encrypted shares of
+    // earlier versions, which kept the file itself in plaintext
+    for share_hash in &encrypted_dirs {
+        problems.push(Problem {
+            description: format!("Encrypted copy {} of a plaintext file is no longer used",
+                base_dir.join(share_hash).display()),
+            repair: Repair::RemovePath(base_dir.join(share_hash)),
+        });
+    }
+
+    // Ownership and permissions of stored files
+    let web_uid = users::get_user_by_name(&config.web_user)
+        .ok_or_else(|| anyhow!("User {} not found", config.web_user))?
+        .uid();
+    let web_gid = users::get_group_by_name(&config.web_group)
+        .ok_or_else(|| anyhow!("Group {} not found", config.web_group))?
+        .gid();
+    for name in uuid_dirs.iter().filter(|uuid| files.contains_key(*uuid)) {
+        let dir = base_dir.join(name);
+        if let Some(path) = find_wrong_permissions(&dir, web_uid, web_gid)? {
+            problems.push(Problem {
+                description: format!("{} is not owned by {}:{} with mode 0750/0640",
+                    path.display(), config.web_user, config.web_group),
+                repair: Repair::FixPermissions(dir),
+            });
+        }
+    }
+
+    if !repair {
+        return Ok(FsckReport { problems, repairs: Vec::new() });
+    }
+
+    let mut repairs = Vec::new();
+    for problem in &problems {
+        let outcome = match &problem.repair {
+            Repair::Skip(reason) => RepairOutcome::Skipped(reason),
+            repair => {
+                let repaired = audit::with_transaction(conn, || {
+                    let action = apply_repair(conn, config, storage, repair)?;
+                    audit::record(conn, actor, Event {
+                        action: "fsck-repair",
+                        details: Some(action.clone()),
+                        ..Event::default()
+                    })?;
+                    Ok(action)
+                });
+                match repaired {
+                    Ok(action) => RepairOutcome::Repaired(action),
+                    Err(e) => RepairOutcome::Failed(e.to_string()),
+                }
+            }
+        };
+        repairs.push(outcome);
+    }
+    Ok(FsckReport { problems, repairs })
+}
+
+/// Returns a description of what was done.
+fn apply_repair(conn: &Connection, config: &Config, storage: &dyn Storage, repair: &Repair) -> Result<String> {
+    let action = match repair {
+        Repair::RemoveFileRecord(file) => {
+            file.remove(conn, storage)?;
+            format!("removed database entry and shares of {} ({})", file.filename, file.uuid)
+        }
+        Repair::RemovePath(path) => {
+            remove_file_with_access(path)?;
+            format!("removed {}", path.display())
+        }
+        Repair::RemoveLink(share_hash) => {
+            remove_share_link(config, share_hash)?;
+            format!("removed link {}", share_hash)
+        }
+        Repair::CreateLink { share_hash, uuid } => {
+            remove_share_link(config, share_hash)?;
+            unix_symlink(uuid, PathBuf::from(&config.base_dir).join(share_hash))?;
+            format!("linked {} -> {}", share_hash, uuid)
+        }
+        Repair::FixPermissions(dir) => {
+            set_permissions_recursive(dir, 0o750, 0o640, &config.web_user, &config.web_group)?;
+            format!("reset ownership and permissions of {}", dir.display())
+        }
+        Repair::Skip(reason) => return Err(anyhow!("skipped: {}", reason)),
+    };
+    Ok(action)
+}
+
+/// Maps the hash of every active share matching `condition` to its file UUID.
+/// Only shares without a password are supposed to have a link.
+fn active_share_links(conn: &Connection, condition: &str) -> Result<BTreeMap<String, String>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT share_hash, uuid FROM shares WHERE active = 1 AND {}", condition
+    ))?;
+    let links = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    links.collect::<Result<BTreeMap<_, _>, _>>().map_err(Into::into)
+}
+
+/// Maps old hashes still within their rotation grace period to their file UUID.
+fn retired_share_links(conn: &Connection) -> Result<BTreeMap<String, String>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.share_hash, r.uuid FROM retired_links r
+         JOIN shares s ON s.uuid = r.uuid AND s.recipient = r.recipient AND s.active = 1
+         WHERE r.date_expires > ?"
+    )?;
+    let links = stmt.query_map(params![Utc::now()], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    links.collect::<Result<BTreeMap<_, _>, _>>().map_err(Into::into)
+}
+
+/// Returns the first path below `path` (inclusive) that does not have the
+/// owner, group and mode `add` gives stored files.
+fn find_wrong_permissions(path: &Path, uid: u32, gid: u32) -> Result<Option<PathBuf>> {
+    let metadata = fs::symlink_metadata(path)?;
+    let expected_mode = if metadata.is_dir() { 0o750 } else { 0o640 };
+
+    if metadata.uid() != uid || metadata.gid() != gid || metadata.mode() & 0o7777 != expected_mode {
+        return Ok(Some(path.to_path_buf()));
+    }
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            if let Some(found) = find_wrong_permissions(&entry?.path(), uid, gid)? {
+                return Ok(Some(found));
+            }
+        }
+    }
+    Ok(None)
+}
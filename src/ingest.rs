@@ -0,0 +1,180 @@
+// src/ingest.rs
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use rusqlite::Connection;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use crate::audit;
+use crate::{Actor, Download, FileShare, ShareInfo, Storage};
+
+/// Line counts of an ingested log.
+#[derive(Debug, Default)]
+pub struct IngestReport {
+    pub lines: usize,
+    /// GET requests recorded as downloads; lines already ingested are not counted
+    pub recorded: usize,
+    /// Requests for a share with any other method
+    pub ignored: usize,
+    pub unparseable: usize,
+}
+
+/// See `Store::ingest_log`; shares that reach their download limit are
+/// removed as `actor`.
+pub(crate) fn ingest_log(
+    conn: &Connection,
+    storage: &dyn Storage,
+    actor: &Actor,
+    path: &str,
+    on_revoke: impl FnMut(&str, &str),
+) -> Result<IngestReport> {
+    let reader: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        let file = File::open(path)
+            .map_err(|e| anyhow!("Failed to open log file {}: {}", path, e))?;
+        Box::new(BufReader::new(file))
+    };
+
+    // Files are ingested in one transaction; stdin is committed as it goes so
+    // that `tail -F access.log | slink ingest-log -` revokes links promptly
+    if path != "-" {
+        audit::with_transaction(conn, || ingest_lines(conn, storage, actor, reader, on_revoke))
+    } else {
+        ingest_lines(conn, storage, actor, reader, on_revoke)
+    }
+}
+
+fn ingest_lines(
+    conn: &Connection,
+    storage: &dyn Storage,
+    actor: &Actor,
+    reader: Box<dyn BufRead>,
+    mut on_revoke: impl FnMut(&str, &str),
+) -> Result<IngestReport> {
+    // Identical lines (same client, second and response) are distinct downloads,
+    // so each line's fingerprint includes how often it has been seen before
+    let mut seen: HashMap<blake3::Hash, usize> = HashMap::new();
+
+    let mut report = IngestReport::default();
+    for line in reader.lines() {
+        let line = line?;
+        report.lines += 1;
+
+        let occurrence = seen.entry(blake3::hash(line.as_bytes())).or_insert(0);
+        *occurrence += 1;
+        let log_entry = blake3::hash(format!("{}#{}", line, occurrence).as_bytes())
+            .to_hex()
+            .to_string();
+
+        let download = match parse_line(&line, log_entry) {
+            Some(LogLine::Download(download)) => download,
+            Some(LogLine::Other) => {
+                report.ignored += 1;
+                continue;
+            }
+            None => {
+                report.unparseable += 1;
+                continue;
+            }
+        };
+
+        if download.record(conn)? {
+            report.recorded += 1;
+            if let Some((uuid, recipient)) =
+                ShareInfo::enforce_download_limit(conn, storage, actor, &download.share_hash)?
+            {
+                let filename = FileShare::find_by_uuid(conn, &uuid)?
+                    .map_or_else(|| uuid.clone(), |f| f.filename);
+                on_revoke(&filename, &recipient);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// A parsed access log line.
+enum LogLine {
+    /// A GET request for a share
+    Download(Download),
+    /// Any other method, e.g. HEAD; like `slink serve`, these are not counted
+    Other,
+}
+
+/// Parses one access log line of the form
+/// `IP IDENT USER [TIME] "METHOD PATH PROTO" STATUS BYTES "REFERER" "AGENT"`.
+/// The referer and agent fields are optional, so common format logs work too.
+fn parse_line(line: &str, log_entry: String) -> Option<LogLine> {
+    let (ip, rest) = line.split_once(' ')?;
+
+    let rest = &rest[rest.find('[')? + 1..];
+    let (time, rest) = rest.split_once(']')?;
+    let date_accessed = DateTime::parse_from_str(time, "%d/%b/%Y:%H:%M:%S %z")
+        .ok()?
+        .with_timezone(&Utc);
+
+    let (request, rest) = quoted_field(rest)?;
+    let mut fields = rest.trim_start().splitn(3, ' ');
+    let status = fields.next()?.parse::<u16>().ok()?;
+    let bytes = match fields.next()? {
+        "-" => 0,
+        bytes => bytes.parse::<u64>().ok()?,
+    };
+
+    // Skip the referer, then take the user agent if present
+    let user_agent = fields.next()
+        .and_then(quoted_field)
+        .and_then(|(_, rest)| quoted_field(rest))
+        .map_or("-".to_string(), |(agent, _)| agent);
+
+    let share_hash = share_hash_from_request(&request)?;
+    if request.split_whitespace().next() != Some("GET") {
+        return Some(LogLine::Other);
+    }
+
+    Some(LogLine::Download(Download {
+        share_hash,
+        date_accessed,
+        ip: ip.to_string(),
+        user_agent,
+        status,
+        bytes,
+        log_entry: Some(log_entry),
+    }))
+}
+
+/// Returns the contents of the next double-quoted field and the remaining input.
+/// Backslash escapes (as written by Apache) are honoured.
+fn quoted_field(input: &str) -> Option<(String, &str)> {
+    let start = input.find('"')? + 1;
+    let mut value = String::new();
+    let mut chars = input[start..].char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            '"' => return Some((value, &input[start + i + 1..])),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Extracts the hash from a request line such as `GET /f/HASH/filename HTTP/1.1`.
+/// Any path prefix in front of the hash is ignored.
+fn share_hash_from_request(request: &str) -> Option<String> {
+    let target = request.split_whitespace().nth(1)?;
+    let path = target.split(['?', '#']).next()?;
+
+    let mut segments = path.rsplit('/');
+    let filename = segments.next()?;
+    let hash = segments.next()?;
+    if filename.is_empty() || hash.is_empty() {
+        return None;
+    }
+    Some(hash.to_string())
+}
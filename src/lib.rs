@@ -0,0 +1,1548 @@
+// src/lib.rs
+
+/// Like `anyhow!`, for a mistake in what the caller asked for; see
+/// `InvalidInput`.
+macro_rules! invalid {
+    ($($arg:tt)*) => { anyhow::Error::from($crate::InvalidInput(format!($($arg)*))) };
+}
+
+pub mod api;
+pub mod audit;
+pub mod crypto;
+pub mod fsck;
+pub mod ingest;
+pub mod mail;
+pub mod migrations;
+pub mod s3;
+pub mod serve;
+pub mod storage;
+mod store;
+pub use audit::Actor;
+pub use storage::Storage;
+pub use store::{AddOutcome, FileListing, InvalidInput, ListFilter, ListSort, PermissionDenied, Statistics, Store};
+pub use store::Verification;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+use dirs::config_dir;
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::{
+    fs::{self, create_dir_all, remove_dir_all, set_permissions, Permissions},
+    path::{Path, PathBuf},
+};
+use nix::unistd::chown;
+use nix::unistd::{Gid, Uid};
+use users::os::unix::GroupExt;
+
+use anyhow::{anyhow, Result};
+use std::io::Write;
+
+/*
+slink is a self-hosted file sharing  utility written in Rust that enables secure
+file sharing through unique URLs. The program  manages files on a web server and
+creates secure, recipient-specific sharing links.
+
+Core functionality:
+- Files are stored with UUIDs in a base directory (e.g., /var/www/UUID/filename)
+- Sharing links are created using keyed BLAKE3 of UUID + recipient identifier
+- File and share information is tracked in SQLite
+- Configuration stored in ~/.config/slink/slink.conf (TOML format), or system-wide in
+  /etc/slink/slink.conf for several operators sharing one database; --config or
+  SLINK_CONFIG name another file, --profile selects a [profiles.NAME] section and
+  SLINK_* variables override single settings
+- Runs on the server side, managing files directly
+
+Command interface:
+- add: Copy files, globs or directory trees (-r) to the managed directory, one UUID per file
+  (identical content is hard linked)
+- share: Create recipient-specific sharing link
+- unshare: Remove sharing link but retain history
+- request: Create, list or close upload links through which others send files
+- show: Display file info and share status
+- ls: List your managed files, or everyone's with --all, filtered by recipient, date, share
+  status or name glob, sorted and paged
+- rm: Remove file and its shares
+- chown: Hand a file over to another user (admins only)
+- expire: Deactivate shares whose expiry time has passed
+- serve: Serve shared files over HTTP(S) without an external web server
+- api: Serve a JSON API for list/add/share/unshare/show/rm (api.rs)
+- token create/ls/rm: Manage bearer tokens for the API
+- ingest-log: Record share downloads from web server access logs
+- db migrate/status: Upgrade the database schema or show its version
+- verify: Rehash stored files and compare against the recorded checksum
+- rotate-secret: Replace the hash secret and move every active share to new links
+- fsck: Cross-check the database against BASE_DIR and optionally repair it
+- fetch: Download an encrypted file and decrypt it with the key from its URL
+- group add/rm/ls: Manage recipient groups, used as @name with share and unshare
+- audit: List the audit log of every change, or verify its hash chain
+
+Library:
+- The CLI (main.rs, commands.rs) is a thin layer over this crate; `Store` in
+  store.rs wraps the configuration and database connection and returns typed
+  values, so other Rust programs can manage shares without parsing output
+
+File structure:
+- Original file: BASE_DIR/UUID/filename
+- Share links: BASE_DIR/HASH -> UUID (relative symlink)
+- Password-protected shares have no link and are only reachable through `slink serve`
+- Encrypted files (`add --encrypt`) are stored as ciphertext under a per-file key
+  (crypto.rs) and shared with ordinary links; the key only appears in the URL
+  fragment and is never stored
+- Storage goes through the `Storage` trait (storage.rs). The layout above is the
+  local backend; the S3 backend (s3.rs) keeps UUID/filename objects in a bucket
+  and publishes a share as a copy at HASH/filename
+
+URL format:
+- Private: https://domain/UUID/filename
+- Shared: https://domain/HASH/filename
+- Upload request: https://domain/upload/HASH/[filename] (POST or PUT, `slink serve` only)
+
+Security considerations:
+- Runs as dedicated user with appropriate permissions
+- Web server must follow symlinks
+- BLAKE3 secret stored in config
+- Share history maintained in SQLite
+- Files and shares record the Unix user who created them (SUDO_USER under sudo);
+  with `admin_group` set, only the owner or a member of that group may share,
+  unshare or remove a file, and secret rotation and repairs are admin-only
+- Every change is recorded in a BLAKE3 hash-chained audit log, which keeps the
+  file name and link of shares whose file was removed
+- API tokens are stored as BLAKE3 hashes and act as the Unix user they were
+  created for
+
+Database schema (versioned with PRAGMA user_version, see migrations.rs):
+- files: uuid, filename, date_added, checksum, size, mime_type, owner, request_hash,
+  encrypted
+- shares: uuid, recipient, share_hash, date_shared, date_removed, active, date_expires,
+  max_downloads, password_hash, encrypted, owner
+- downloads: uuid, recipient, share_hash, date_accessed, ip, user_agent, status, bytes,
+  log_entry
+- retired_links: share_hash, uuid, recipient, date_retired, date_expires
+- recipients: group_name, recipient, date_added, owner
+- audit: id, timestamp, user, ssh_client, command, action, uuid, filename, recipient,
+  share_hash, details, prev_hash, hash, remote_addr (append-only, see audit.rs)
+- api_tokens: name, token_hash, user, date_created, date_last_used
+- upload_requests: request_hash, recipient, name_hint, owner, date_created, date_expires,
+  active
+
+Configuration (slink.conf):
+- base_url: Web server URL
+- base_dir: File storage location
+- db_path: SQLite database path
+- hash_secret: Secret for hash generation
+- web_user: Owner of files
+- web_group: Group for web access
+- hash_bytes: Length of resulting hash before base64 encoding
+- admin_group: Optional Unix group whose members may manage every user's files;
+  without it every user is an admin (single-user mode)
+- [mail]: Optional SMTP or sendmail settings and templates for `share --notify`
+- [storage]: Optional backend selection, `local` (default) or `s3`
+- [profiles.NAME]: Optional named overrides of the settings above
+- [remote]: On a client, run every command on a server over SSH instead (see
+  remote.rs in the CLI); no other settings are needed then
+
+The program is  designed to be simple, secure, and  maintainable, following Unix
+philosophy of doing one thing well.  It integrates with existing web servers and
+provides a straightforward CLI for file sharing management.
+*/
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub base_url: String,
+    pub base_dir: String,
+    pub db_path: String,
+    pub hash_secret: String,
+    pub web_user: String,
+    pub web_group: String,
+    pub hash_bytes: usize,
+    /// Unix group allowed to manage files owned by other users. Without it
+    /// ownership is recorded but not enforced.
+    pub admin_group: Option<String>,
+    pub mail: Option<MailConfig>,
+    pub storage: Option<StorageConfig>,
+    /// Name of the `[profiles.NAME]` section applied on load, if any
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+/// A `[profiles.NAME]` section. Selected with `--profile NAME` or
+/// SLINK_PROFILE, its settings replace the top-level ones, so one file can
+/// serve several sites with their own `base_url`, `base_dir` and `db_path`.
+#[derive(Debug, Default, Deserialize)]
+struct Profile {
+    base_url: Option<String>,
+    base_dir: Option<String>,
+    db_path: Option<String>,
+    hash_secret: Option<String>,
+    web_user: Option<String>,
+    web_group: Option<String>,
+    hash_bytes: Option<usize>,
+    admin_group: Option<String>,
+    mail: Option<MailConfig>,
+    storage: Option<StorageConfig>,
+}
+
+#[derive(Deserialize)]
+struct Profiles {
+    #[serde(default)]
+    profiles: std::collections::BTreeMap<String, Profile>,
+}
+
+/// `[remote]` section of a client configuration. When present, commands are
+/// run by `slink` on `host` over SSH instead of locally, and the file needs
+/// no other settings. Profiles may have their own `[profiles.NAME.remote]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteConfig {
+    /// SSH destination, e.g. `files.example.com` or `alice@files.example.com`
+    pub host: String,
+    /// Command line that runs slink on the server, `slink` by default; e.g.
+    /// `sudo slink` or `/usr/local/bin/slink --config /etc/slink/site.conf`
+    pub command: Option<String>,
+    /// Extra ssh arguments, e.g. `["-p", "2222"]`
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+    /// Profile to use on the server
+    pub profile: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RemoteProfile {
+    remote: Option<RemoteConfig>,
+}
+
+#[derive(Deserialize)]
+struct Remotes {
+    remote: Option<RemoteConfig>,
+    #[serde(default)]
+    profiles: std::collections::BTreeMap<String, RemoteProfile>,
+}
+
+impl RemoteConfig {
+    /// Reads the `[remote]` section from `config_path`, if the file exists and
+    /// has one. A `profile` defined in the file selects its own section or
+    /// inherits the top-level one; any other profile is passed on to the
+    /// server.
+    pub fn load(config_path: &Path, profile: Option<&str>) -> Result<Option<RemoteConfig>> {
+        let Ok(content) = fs::read_to_string(config_path) else {
+            return Ok(None);
+        };
+        let mut remotes: Remotes = toml::from_str(&content)?;
+
+        Ok(match profile.and_then(|name| remotes.profiles.remove(name)) {
+            Some(local_profile) => local_profile.remote.or(remotes.remote),
+            None => remotes.remote.map(|remote| RemoteConfig {
+                profile: profile.map(str::to_string).or(remote.profile),
+                ..remote
+            }),
+        })
+    }
+}
+
+/// Configuration shared by every operator on the machine, used when a user
+/// has no configuration of their own.
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/slink/slink.conf";
+
+/// Optional `[mail]` section used by `share --notify`. Either `smtp_host` or
+/// `sendmail_path` selects how messages are sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailConfig {
+    /// Sender address, e.g. `Files <files@example.com>`
+    pub from: String,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    /// `starttls` (default), `tls` or `none`
+    pub smtp_security: Option<String>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub sendmail_path: Option<String>,
+    /// Subject and body templates, see mail.rs for the placeholders
+    pub subject: Option<String>,
+    pub body: Option<String>,
+}
+
+/// Optional `[storage]` section. Without it files are kept in BASE_DIR.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Local,
+    S3(S3Config),
+}
+
+/// Settings of an S3-compatible bucket. With this backend BASE_DIR only
+/// holds uploads while they are being hashed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-central-1.amazonaws.com` or `http://127.0.0.1:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    /// Defaults to `us-east-1`, which MinIO accepts as well
+    pub region: Option<String>,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Address the bucket as `endpoint/bucket` (default) rather than
+    /// `bucket.endpoint`
+    pub path_style: Option<bool>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct FileShare {
+    pub uuid: String,
+    pub filename: String,
+    pub date_added: DateTime<Utc>,
+    // Not recorded for files added before checksums were stored
+    pub checksum: Option<String>,
+    pub size: Option<u64>,
+    pub mime_type: Option<String>,
+    /// Unix user who added the file; `None` for files from before ownership
+    /// was recorded, which anyone may manage
+    pub owner: Option<String>,
+    /// The stored data is ciphertext from `slink add --encrypt`, see crypto.rs
+    pub encrypted: bool,
+}
+
+#[derive(Serialize)]
+pub struct ShareInfo {
+    pub recipient: String,
+    pub share_hash: String,
+    pub date_shared: DateTime<Utc>,
+    pub date_removed: Option<DateTime<Utc>>,
+    pub date_expires: Option<DateTime<Utc>>,
+    pub active: bool,
+    pub max_downloads: Option<u32>,
+    pub password_protected: bool,
+    pub encrypted: bool,
+    pub downloads: i64,
+    pub last_accessed: Option<DateTime<Utc>>,
+    /// Unix user who created the share
+    pub owner: Option<String>,
+}
+
+/// A named list of recipients, addressed as `@name` in `share` and `unshare`.
+#[derive(Serialize)]
+pub struct RecipientGroup {
+    pub name: String,
+    pub members: Vec<String>,
+    /// User who created the group; `None` for groups from before multi-user mode
+    pub owner: Option<String>,
+}
+
+/// A bearer token for `slink api`. Only a hash of the token is stored;
+/// requests made with it act as `user`.
+#[derive(Serialize)]
+pub struct ApiToken {
+    pub name: String,
+    pub user: String,
+    pub date_created: DateTime<Utc>,
+    pub date_last_used: Option<DateTime<Utc>>,
+}
+
+/// A link through which `recipient` can send files, which are stored like
+/// `slink add` does and belong to `owner`. Uploads go to `slink serve`.
+#[derive(Serialize)]
+pub struct UploadRequest {
+    pub request_hash: String,
+    pub recipient: String,
+    /// Suggested file name, used when the upload does not name the file
+    pub name_hint: Option<String>,
+    pub owner: String,
+    pub date_created: DateTime<Utc>,
+    pub date_expires: Option<DateTime<Utc>>,
+    pub active: bool,
+    /// Number of stored files received through the request
+    pub uploads: i64,
+}
+
+/// A share moved to a new link by a hash secret rotation.
+pub struct RotatedShare {
+    pub uuid: String,
+    pub recipient: String,
+    pub old_hash: String,
+    pub new_hash: String,
+    /// The new link needs the key from the old one appended
+    pub encrypted: bool,
+}
+
+/// Optional restrictions applied when creating a share.
+#[derive(Debug, Default)]
+pub struct ShareOptions {
+    pub expires: Option<DateTime<Utc>>,
+    pub max_downloads: Option<u32>,
+    /// Only stored as a salted hash, see `hash_password`
+    pub password: Option<String>,
+    /// Refuse to share unless the file is stored encrypted
+    pub encrypt: bool,
+}
+
+/// A single request for a shared file, as seen in a web server access log.
+struct Download {
+    share_hash: String,
+    date_accessed: DateTime<Utc>,
+    ip: String,
+    user_agent: String,
+    status: u16,
+    bytes: u64,
+    /// Fingerprint of the originating log line; `None` when recorded live
+    log_entry: Option<String>,
+}
+
+/// Shortest hash secret `slink init` and SLINK_HASH_SECRET accept. Generated
+/// secrets have 36 characters.
+pub const MIN_HASH_SECRET_CHARS: usize = 16;
+
+impl Config {
+    /// Fails if `config_path` is readable by more than it needs to be. The
+    /// file holds the hash secret, so callers should warn about this.
+    pub fn check_permissions(config_path: &Path) -> Result<()> {
+        let metadata = fs::metadata(config_path)?;
+        let mode = metadata.permissions().mode();
+
+        // The system-wide file must be readable by the operators' group
+        if config_path == Path::new(SYSTEM_CONFIG_PATH) {
+            if mode & 0o027 != 0 {
+                return Err(anyhow!("Config file permissions too loose. Use chmod 640 {}",
+                    config_path.display()));
+            }
+            return Ok(());
+        }
+
+        // Check if file is readable by group or others
+        if mode & 0o077 != 0 {
+            return Err(anyhow!("Config file permissions too loose. Use chmod 600 {}",
+                config_path.display()));
+        }
+        Ok(())
+    }
+
+    /// The per-user configuration if it exists, otherwise the system-wide one
+    /// if that exists.
+    pub fn path() -> Result<PathBuf> {
+        let user_path = Config::user_path()?;
+        if !user_path.exists() && Path::new(SYSTEM_CONFIG_PATH).exists() {
+            return Ok(PathBuf::from(SYSTEM_CONFIG_PATH));
+        }
+        Ok(user_path)
+    }
+
+    /// `path` if given, otherwise SLINK_CONFIG if set.
+    pub fn explicit_path(path: Option<&Path>) -> Option<PathBuf> {
+        path.map(Path::to_path_buf)
+            .or_else(|| std::env::var_os("SLINK_CONFIG").map(PathBuf::from))
+    }
+
+    /// The file to load: `path`, SLINK_CONFIG or the default from `path()`.
+    pub fn resolve_path(path: Option<&Path>) -> Result<PathBuf> {
+        Config::explicit_path(path).map_or_else(Config::path, Ok)
+    }
+
+    pub fn user_path() -> Result<PathBuf> {
+        Ok(config_dir()
+            .ok_or_else(|| anyhow!("Could not determine config directory"))?
+            .join("slink")
+            .join("slink.conf"))
+    }
+
+    /// Whether `user` may manage files owned by others: root and members of
+    /// `admin_group`, or everyone if no admin group is configured.
+    pub fn is_admin(&self, user: &str) -> bool {
+        let group_name = match &self.admin_group {
+            Some(group_name) => group_name,
+            None => return true,
+        };
+        if user == "root" {
+            return true;
+        }
+        let (group, user) = match (users::get_group_by_name(group_name), users::get_user_by_name(user)) {
+            (Some(group), Some(user)) => (group, user),
+            _ => return false,
+        };
+        user.primary_group_id() == group.gid()
+            || group.members().iter().any(|member| member.as_os_str() == user.name())
+    }
+
+    /// Loads the file named by SLINK_CONFIG or the default location, with the
+    /// profile named by SLINK_PROFILE, if any.
+    pub fn load_or_create() -> Result<Self> {
+        let profile = std::env::var("SLINK_PROFILE").ok();
+        Config::load(&Config::resolve_path(None)?, profile.as_deref())
+    }
+
+    /// Loads `config_path`, applies the `[profiles.NAME]` section `profile`
+    /// and then any SLINK_* environment overrides (see `apply_env`).
+    /// File permissions are left to `check_permissions`.
+    pub fn load(config_path: &Path, profile: Option<&str>) -> Result<Self> {
+        if !config_path.exists() {
+            return Err(anyhow!(
+                "Configuration file {} not found. Please run `slink init` to create one.",
+                config_path.display()
+            ));
+        }
+
+        let content = fs::read_to_string(config_path)
+            .map_err(|e| anyhow!("Failed to read config file {}: {}", config_path.display(), e))?;
+        let mut config: Config = toml::from_str(&content)?;
+
+        if let Some(name) = profile {
+            let profile = toml::from_str::<Profiles>(&content)?.profiles.remove(name)
+                .ok_or_else(|| anyhow!("No profile {} in {}", name, config_path.display()))?;
+            config.apply_profile(name, profile);
+        }
+        config.apply_env()?;
+
+        if !Path::new(&config.db_path).exists() {
+            // Try to initialize database
+            init_database(&config.db_path)
+                .map_err(|e| anyhow!("Failed to initialize database {}: {}", config.db_path, e))?;
+        }
+        Ok(config)
+    }
+
+    fn apply_profile(&mut self, name: &str, profile: Profile) {
+        self.base_url = profile.base_url.unwrap_or(self.base_url.clone());
+        self.base_dir = profile.base_dir.unwrap_or(self.base_dir.clone());
+        self.db_path = profile.db_path.unwrap_or(self.db_path.clone());
+        self.hash_secret = profile.hash_secret.unwrap_or(self.hash_secret.clone());
+        self.web_user = profile.web_user.unwrap_or(self.web_user.clone());
+        self.web_group = profile.web_group.unwrap_or(self.web_group.clone());
+        self.hash_bytes = profile.hash_bytes.unwrap_or(self.hash_bytes);
+        self.admin_group = profile.admin_group.or(self.admin_group.take());
+        self.mail = profile.mail.or(self.mail.take());
+        self.storage = profile.storage.or(self.storage.take());
+        self.profile = Some(name.to_string());
+    }
+
+    /// Lets SLINK_BASE_URL, SLINK_BASE_DIR, SLINK_DB_PATH, SLINK_HASH_SECRET,
+    /// SLINK_WEB_USER, SLINK_WEB_GROUP, SLINK_HASH_BYTES and SLINK_ADMIN_GROUP
+    /// replace the corresponding settings. An empty SLINK_ADMIN_GROUP removes
+    /// the admin group. Values must pass the same checks as in `slink init`.
+    fn apply_env(&mut self) -> Result<()> {
+        let var = |name: &str, check: fn(&str) -> Result<(), &'static str>| {
+            std::env::var(name).ok()
+                .map(|value| match check(&value) {
+                    Ok(()) => Ok(value),
+                    Err(err) => Err(anyhow!("{}: {} (got {})", name, err, value)),
+                })
+                .transpose()
+        };
+        if let Some(value) = var("SLINK_BASE_URL", Config::check_base_url)? {
+            self.base_url = value;
+        }
+        if let Some(value) = var("SLINK_BASE_DIR", Config::check_base_dir)? {
+            self.base_dir = value;
+        }
+        if let Some(value) = var("SLINK_DB_PATH", |_| Ok(()))? {
+            self.db_path = value;
+        }
+        if let Ok(value) = std::env::var("SLINK_HASH_SECRET") {
+            // Not echoed like the others
+            Config::check_hash_secret(&value).map_err(|err| anyhow!("SLINK_HASH_SECRET: {}", err))?;
+            self.hash_secret = value;
+        }
+        if let Some(value) = var("SLINK_WEB_USER", Config::check_web_user)? {
+            self.web_user = value;
+        }
+        if let Some(value) = var("SLINK_WEB_GROUP", Config::check_web_group)? {
+            self.web_group = value;
+        }
+        if let Some(value) = var("SLINK_HASH_BYTES", Config::check_hash_bytes)? {
+            self.hash_bytes = value.parse()?;
+        }
+        if let Some(value) = var("SLINK_ADMIN_GROUP", |group| match group {
+            "" => Ok(()),
+            group => Config::check_admin_group(group),
+        })? {
+            self.admin_group = Some(value).filter(|group| !group.is_empty());
+        }
+        Ok(())
+    }
+
+    // Checks of single settings, shared by `slink init` and `apply_env`
+
+    pub fn check_base_url(input: &str) -> Result<(), &'static str> {
+        if input.starts_with("http://") || input.starts_with("https://") {
+            Ok(())
+        } else {
+            Err("Base URL must start with http:// or https://")
+        }
+    }
+
+    pub fn check_base_dir(input: &str) -> Result<(), &'static str> {
+        if Path::new(input).is_dir() {
+            Ok(())
+        } else {
+            Err("Base directory must exist and be a valid directory")
+        }
+    }
+
+    pub fn check_hash_secret(input: &str) -> Result<(), &'static str> {
+        if input.trim().chars().count() < MIN_HASH_SECRET_CHARS {
+            Err("Hash secret must be at least 16 characters long")
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn check_web_user(input: &str) -> Result<(), &'static str> {
+        users::get_user_by_name(input).map(|_| ()).ok_or("Web user must exist")
+    }
+
+    pub fn check_web_group(input: &str) -> Result<(), &'static str> {
+        users::get_group_by_name(input).map(|_| ()).ok_or("Web group must exist")
+    }
+
+    pub fn check_admin_group(input: &str) -> Result<(), &'static str> {
+        users::get_group_by_name(input).map(|_| ()).ok_or("Admin group must exist")
+    }
+
+    pub fn check_hash_bytes(input: &str) -> Result<(), &'static str> {
+        match input.parse::<usize>() {
+            Ok(value) if (2..=32).contains(&value) => Ok(()),
+            Ok(_) => Err("Hash bytes must be between 2 and 32"),
+            Err(_) => Err("Hash bytes must be a number"),
+        }
+    }
+
+    /// Stores a new hash secret in `config_path` without touching any other
+    /// setting: in the section of the loaded profile, or at the top level. In
+    /// the latter case profiles with their own database that inherited the old
+    /// secret keep it, since their links were derived from it.
+    pub fn save_hash_secret(&self, config_path: &Path, new_secret: &str) -> Result<()> {
+        let content = fs::read_to_string(config_path)
+            .map_err(|e| anyhow!("Failed to read config file {}: {}", config_path.display(), e))?;
+        let mut table: toml::Table = content.parse()?;
+
+        match &self.profile {
+            Some(name) => {
+                let profile = table.get_mut("profiles")
+                    .and_then(|profiles| profiles.get_mut(name))
+                    .and_then(|profile| profile.as_table_mut())
+                    .ok_or_else(|| anyhow!("No profile {} in {}", name, config_path.display()))?;
+                profile.insert("hash_secret".to_string(), new_secret.into());
+            }
+            None => {
+                let old_secret = table.get("hash_secret").cloned();
+                let profiles = table.get_mut("profiles").and_then(|profiles| profiles.as_table_mut());
+                if let (Some(old_secret), Some(profiles)) = (old_secret, profiles) {
+                    let separate = profiles.iter_mut().map(|(_, profile)| profile)
+                        .filter_map(|profile| profile.as_table_mut())
+                        .filter(|profile| profile.contains_key("db_path"));
+                    for profile in separate {
+                        profile.entry("hash_secret").or_insert(old_secret.clone());
+                    }
+                }
+                table.insert("hash_secret".to_string(), new_secret.into());
+            }
+        }
+
+        write_atomically(config_path, &toml::to_string(&table)?)
+    }
+
+    /// Writes the configuration atomically, see `write_atomically`.
+    pub fn save(&self, config_path: &Path) -> Result<()> {
+        write_atomically(config_path, &toml::to_string(self)?)
+    }
+}
+
+/// Writes a configuration file atomically: a 0600 temporary file in the same
+/// directory is renamed over the old one, so readers never see a partial file.
+/// An existing file keeps its mode and group.
+fn write_atomically(config_path: &Path, content: &str) -> Result<()> {
+    let dir = config_path.parent()
+        .ok_or_else(|| anyhow!("Invalid config path {}", config_path.display()))?;
+    create_dir_all(dir)
+        .map_err(|e| anyhow!("Failed to create config directory: {}", e))?;
+
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    if let Ok(existing) = fs::metadata(config_path) {
+        chown(temp.path(), None, Some(Gid::from_raw(existing.gid())))?;
+        temp.as_file().set_permissions(existing.permissions())?;
+    }
+    temp.write_all(content.as_bytes())?;
+    temp.as_file().sync_all()?;
+    temp.persist(config_path)
+        .map_err(|e| anyhow!("Failed to write config file {}: {}", config_path.display(), e))?;
+    Ok(())
+}
+
+pub fn init_database(db_path: &str) -> Result<()> {
+    let mut conn = Connection::open(db_path)?;
+    migrations::migrate(&mut conn)?;
+    Ok(())
+}
+
+fn calculate_share_hash(uuid: &str, recipient: &str, secret: &str, hash_bytes: usize) -> Result<String> {
+    let key = blake3::derive_key("slink", secret.as_bytes());
+    let keyed_hash = blake3::keyed_hash(
+        &key,
+        format!("{}{}", uuid, recipient).as_bytes(),
+    );
+
+    Ok(b64.encode(&keyed_hash.as_bytes()[..hash_bytes]))
+}
+
+fn set_permissions_recursive(
+    path: &Path,
+    dir_mode: u32,
+    file_mode: u32,
+    web_user: &str,
+    web_group: &str,
+) -> Result<()> {
+    // Resolve the user and group IDs
+    let web_uid = users::get_user_by_name(web_user)
+        .ok_or_else(|| anyhow::anyhow!("User {} not found", web_user))?
+        .uid();
+    let web_gid = users::get_group_by_name(web_group)
+        .ok_or_else(|| anyhow::anyhow!("Group {} not found", web_group))?
+        .gid();
+
+    // Get current user's UID and primary GID
+    let current_uid = nix::unistd::getuid();
+    let current_gid = nix::unistd::getgid();
+
+    if path.is_dir() {
+        // Set directory permissions to allow owner access first
+        set_permissions(path, Permissions::from_mode(0o700))?;
+
+        // Change ownership to current user temporarily
+        chown(path, Some(current_uid), Some(current_gid))?;
+
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            set_permissions_recursive(&entry.path(), dir_mode, file_mode, web_user, web_group)?;
+        }
+
+        // Now set final permissions and ownership
+        set_permissions(path, Permissions::from_mode(dir_mode))?;
+        chown(path, Some(Uid::from_raw(web_uid)), Some(Gid::from_raw(web_gid)))?;
+    } else {
+        // For files, temporarily make them fully accessible to owner
+        set_permissions(path, Permissions::from_mode(0o600))?;
+        chown(path, Some(current_uid), Some(current_gid))?;
+
+        // Set final permissions and ownership
+        set_permissions(path, Permissions::from_mode(file_mode))?;
+        chown(path, Some(Uid::from_raw(web_uid)), Some(Gid::from_raw(web_gid)))?;
+    }
+    Ok(())
+}
+
+/// Hashes a share password with Argon2id and a random salt. The result is a
+/// PHC string that carries the salt and parameters along with the hash.
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+}
+
+/// Returns a random passphrase of 16 URL-safe characters (96 bits).
+pub fn generate_password() -> String {
+    let mut bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut bytes);
+    b64.encode(bytes)
+}
+
+/// Parses either a relative duration (`30m`, `12h`, `7d`, `2w`) or an absolute
+/// timestamp (RFC 3339, `YYYY-MM-DD HH:MM:SS` or `YYYY-MM-DD`, taken as UTC).
+pub fn parse_expiry(input: &str) -> Result<DateTime<Utc>> {
+    let input = input.trim();
+    if let Some(duration) = parse_duration(input)? {
+        return Ok(Utc::now() + duration);
+    }
+
+    let expires = parse_timestamp(input)
+        .ok_or_else(|| invalid!("Invalid expiry '{}': use a duration like 7d or a timestamp", input))?;
+    if expires <= Utc::now() {
+        return Err(invalid!("Expiry time is in the past"));
+    }
+    Ok(expires)
+}
+
+/// Like `parse_expiry`, but a duration counts back from now, so `7d` is a
+/// week ago.
+pub fn parse_past_time(input: &str) -> Result<DateTime<Utc>> {
+    let input = input.trim();
+    if let Some(duration) = parse_duration(input)? {
+        return Ok(Utc::now() - duration);
+    }
+    parse_timestamp(input)
+        .ok_or_else(|| invalid!("Invalid time '{}': use a duration like 7d or a timestamp", input))
+}
+
+/// A positive duration like `7d`, or `None` if `input` is not one.
+fn parse_duration(input: &str) -> Result<Option<Duration>> {
+    let Some(unit) = input.chars().last().filter(|c| c.is_ascii_alphabetic()) else {
+        return Ok(None);
+    };
+    let Ok(amount) = input[..input.len() - 1].parse::<i64>() else {
+        return Ok(None);
+    };
+
+    let duration = match unit {
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => return Err(invalid!("Unknown duration unit '{}' (use m, h, d or w)", unit)),
+    }.ok_or_else(|| invalid!("Duration out of range: {}", input))?;
+
+    if amount <= 0 {
+        return Err(invalid!("Duration must be positive"));
+    }
+    Ok(Some(duration))
+}
+
+fn parse_timestamp(input: &str) -> Option<DateTime<Utc>> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(input) {
+        Some(dt.with_timezone(&Utc))
+    } else if let Ok(dt) = NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S") {
+        Some(dt.and_utc())
+    } else {
+        NaiveDate::parse_from_str(input, "%Y-%m-%d").ok()
+            .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+    }
+}
+
+fn remove_share_link(config: &Config, share_hash: &str) -> Result<()> {
+    let link = PathBuf::from(&config.base_dir).join(share_hash);
+    // Use symlink_metadata so dangling links are removed as well
+    match link.symlink_metadata() {
+        // Encrypted shares of earlier versions are a directory holding a copy
+        Ok(metadata) if metadata.is_dir() => remove_file_with_access(&link)?,
+        Ok(_) => fs::remove_file(link)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+fn remove_file_with_access(path: &Path) -> Result<()> {
+    // Get current user's UID and GID
+    let current_uid = nix::unistd::getuid();
+    let current_gid = nix::unistd::getgid();
+
+    // Temporarily take ownership and full permissions
+    chown(path, Some(current_uid), Some(current_gid))?;
+    set_permissions(path, Permissions::from_mode(0o700))?;
+
+    if path.is_dir() {
+        remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }.map_err(Into::into)
+}
+
+
+impl FileShare {
+    /// Files named `filename`, oldest first. With `owner`, only that user's
+    /// files and those without an owner.
+    fn find_by_name(conn: &Connection, filename: &str, owner: Option<&str>) -> Result<Vec<(String, DateTime<Utc>)>> {
+        let mut stmt = conn.prepare(
+            "SELECT uuid, date_added FROM files
+             WHERE filename = ?1 AND (?2 IS NULL OR owner = ?2 OR owner IS NULL)
+             ORDER BY date_added"
+        )?;
+
+        let results = stmt.query_map(params![filename, owner], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+
+        results.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Column list matching `from_row`, for use in SELECT statements.
+    const COLUMNS: &'static str =
+        "f.uuid, f.filename, f.date_added, f.checksum, f.size, f.mime_type, f.owner, f.encrypted";
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<FileShare> {
+        Ok(FileShare {
+            uuid: row.get(0)?,
+            filename: row.get(1)?,
+            date_added: row.get(2)?,
+            checksum: row.get(3)?,
+            size: row.get(4)?,
+            mime_type: row.get(5)?,
+            owner: row.get(6)?,
+            encrypted: row.get(7)?,
+        })
+    }
+
+    fn find_by_uuid(conn: &Connection, uuid: &str) -> Result<Option<FileShare>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM files f WHERE f.uuid = ?", FileShare::COLUMNS
+        ))?;
+
+        let mut rows = stmt.query([uuid])?;
+
+        if let Some(row) = rows.next()? {
+            Ok(Some(FileShare::from_row(row)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn find_all(conn: &Connection) -> Result<Vec<FileShare>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM files f ORDER BY f.date_added", FileShare::COLUMNS
+        ))?;
+
+        let files = stmt.query_map([], FileShare::from_row)?;
+        files.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Finds the oldest file with the given content whose stored copy is still
+    /// present with the expected size, only among `owner`'s files if given.
+    fn find_by_checksum(
+        conn: &Connection,
+        storage: &dyn Storage,
+        checksum: &str,
+        size: u64,
+        owner: Option<&str>,
+    ) -> Result<Option<FileShare>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM files f
+             WHERE f.checksum = ?1 AND f.size = ?2 AND (?3 IS NULL OR f.owner = ?3)
+             ORDER BY f.date_added",
+            FileShare::COLUMNS
+        ))?;
+
+        let files = stmt.query_map(params![checksum, size, owner], FileShare::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for file in files {
+            if storage.size(&file.uuid, &file.filename)? == Some(size) {
+                return Ok(Some(file));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn path(&self, config: &Config) -> PathBuf {
+        PathBuf::from(&config.base_dir).join(&self.uuid).join(&self.filename)
+    }
+
+    fn remove(&self, conn: &Connection, storage: &dyn Storage) -> Result<()> {
+    // Unpublish every link the database knows about, then drop the data
+    let mut stmt = conn.prepare(
+        "SELECT share_hash FROM shares WHERE uuid = ?1 AND active = 1
+         UNION SELECT share_hash FROM retired_links WHERE uuid = ?1"
+    )?;
+    let links = stmt.query_map([&self.uuid], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for share_hash in &links {
+        storage.unpublish(share_hash)?;
+    }
+    storage.remove(&self.uuid, &self.filename)?;
+
+    // Update database
+    conn.execute(
+        "UPDATE shares SET active = 0, date_removed = ? WHERE uuid = ?",
+        params![Utc::now(), self.uuid],
+    )?;
+
+    conn.execute("DELETE FROM retired_links WHERE uuid = ?", [&self.uuid])?;
+    conn.execute("DELETE FROM files WHERE uuid = ?", [&self.uuid])?;
+
+    Ok(())
+}
+
+}
+
+impl ShareInfo {
+    fn share(
+        conn: &Connection,
+        config: &Config,
+        storage: &dyn Storage,
+        file: &FileShare,
+        recipient: &str,
+        options: &ShareOptions,
+        owner: &str,
+    ) -> Result<String> {
+        let uuid = &file.uuid;
+        let share_hash = calculate_share_hash(uuid, recipient, &config.hash_secret, config.hash_bytes)?;
+
+        // Remove existing links, including one stored under a different hash
+        storage.unpublish(&share_hash)?;
+        let stored_hash = conn.query_row(
+            "SELECT share_hash FROM shares WHERE uuid = ? AND recipient = ? AND active = 1",
+            [uuid, recipient],
+            |row| row.get::<_, String>(0),
+        ).optional()?;
+        if let Some(stored_hash) = stored_hash.filter(|stored_hash| *stored_hash != share_hash) {
+            storage.unpublish(&stored_hash)?;
+        }
+        // A link would let the web server hand out the file without asking for
+        // the password, so protected shares are left to `slink serve`. The data
+        // of an encrypted file is ciphertext and is published as it is.
+        if options.password.is_none() {
+            storage.publish(&share_hash, uuid, &file.filename)?;
+        }
+
+        let password_hash = options.password.as_deref().map(hash_password).transpose()?;
+
+        // Use REPLACE INTO or INSERT OR REPLACE to handle existing shares
+        conn.execute(
+            "INSERT OR REPLACE INTO shares
+                (uuid, recipient, share_hash, date_shared, date_expires, max_downloads,
+                 password_hash, encrypted, owner, active)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, 1)",
+            params![
+                uuid,
+                recipient,
+                share_hash,
+                Utc::now(),
+                options.expires,
+                options.max_downloads,
+                password_hash,
+                file.encrypted,
+                owner
+            ],
+        )?;
+
+        Ok(share_hash)
+    }
+
+
+    fn unshare(conn: &Connection, storage: &dyn Storage, uuid: &str, recipient: &str) -> Result<()> {
+        // The link is the stored hash, which need not match the current secret
+        let share_hash = conn.query_row(
+            "SELECT share_hash FROM shares WHERE uuid = ? AND recipient = ? AND active = 1",
+            [uuid, recipient],
+            |row| row.get::<_, String>(0),
+        ).optional()?;
+
+        // Remove link, including any kept alive after a secret rotation
+        if let Some(share_hash) = share_hash {
+            storage.unpublish(&share_hash)?;
+        }
+        ShareInfo::remove_retired_links(conn, storage, uuid, recipient)?;
+
+        conn.execute(
+            "UPDATE shares SET active = 0, date_removed = ? 
+             WHERE uuid = ? AND recipient = ? AND active = 1",
+            params![Utc::now(), uuid, recipient],
+        )?;
+
+        Ok(())
+    }
+
+    fn get_shares(conn: &Connection, uuid: &str) -> Result<Vec<ShareInfo>> {
+        let mut stmt = conn.prepare(
+            "SELECT s.recipient, s.share_hash, s.date_shared, s.date_removed, s.date_expires, s.active,
+                    s.max_downloads, s.password_hash IS NOT NULL, s.encrypted, s.owner,
+                    (SELECT COUNT(*) FROM downloads d
+                     WHERE d.uuid = s.uuid AND d.recipient = s.recipient
+                       AND d.status = 200),
+                    (SELECT MAX(d.date_accessed) FROM downloads d
+                     WHERE d.uuid = s.uuid AND d.recipient = s.recipient
+                       AND d.status = 200)
+             FROM shares s WHERE s.uuid = ?"
+        )?;
+
+        let shares = stmt.query_map([uuid], |row| {
+            Ok(ShareInfo {
+                recipient: row.get(0)?,
+                share_hash: row.get(1)?,
+                date_shared: row.get(2)?,
+                date_removed: row.get(3)?,
+                date_expires: row.get(4)?,
+                active: row.get(5)?,
+                max_downloads: row.get(6)?,
+                password_protected: row.get(7)?,
+                encrypted: row.get(8)?,
+                owner: row.get(9)?,
+                downloads: row.get(10)?,
+                last_accessed: row.get(11)?,
+            })
+        })?;
+
+        shares.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Revokes the active share behind `share_hash` once its successful downloads
+    /// reach the share's limit, recording `actor` in the audit log. Returns the
+    /// (uuid, recipient) pair if revoked.
+    fn enforce_download_limit(
+        conn: &Connection,
+        storage: &dyn Storage,
+        actor: &Actor,
+        share_hash: &str,
+    ) -> Result<Option<(String, String)>> {
+        let share = conn.query_row(
+            "SELECT s.uuid, s.recipient, s.max_downloads,
+                    (SELECT COUNT(*) FROM downloads d
+                     WHERE d.uuid = s.uuid AND d.recipient = s.recipient
+                       AND d.status = 200)
+             FROM shares s
+             WHERE s.share_hash = ? AND s.active = 1 AND s.max_downloads IS NOT NULL",
+            [share_hash],
+            |row| Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+            )),
+        ).optional()?;
+
+        match share {
+            Some((uuid, recipient, max_downloads, downloads)) if downloads >= max_downloads => {
+                audit::with_transaction(conn, || {
+                    ShareInfo::unshare(conn, storage, &uuid, &recipient)?;
+                    audit::record(conn, actor, audit::Event {
+                        action: "revoke",
+                        filename: FileShare::find_by_uuid(conn, &uuid)?.map(|file| file.filename),
+                        uuid: Some(uuid.clone()),
+                        recipient: Some(recipient.clone()),
+                        share_hash: Some(share_hash.to_string()),
+                        details: Some(format!("download limit of {} reached", max_downloads)),
+                    })
+                })?;
+                Ok(Some((uuid, recipient)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Deactivates every active share whose expiry has passed and returns
+    /// the affected (uuid, recipient) pairs.
+    fn expire_overdue(conn: &Connection, storage: &dyn Storage) -> Result<Vec<(String, String)>> {
+        let now = Utc::now();
+        let mut stmt = conn.prepare(
+            "SELECT uuid, recipient, share_hash FROM shares
+             WHERE active = 1 AND date_expires IS NOT NULL AND date_expires <= ?"
+        )?;
+
+        let overdue = stmt.query_map([now], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+        })?.collect::<Result<Vec<_>, _>>()?;
+
+        let mut expired = Vec::new();
+        for (uuid, recipient, share_hash) in overdue {
+            storage.unpublish(&share_hash)?;
+            ShareInfo::remove_retired_links(conn, storage, &uuid, &recipient)?;
+            conn.execute(
+                "UPDATE shares SET active = 0, date_removed = ?
+                 WHERE uuid = ? AND recipient = ? AND active = 1",
+                params![now, uuid, recipient],
+            )?;
+            expired.push((uuid, recipient));
+        }
+
+        Ok(expired)
+    }
+
+    /// Removes old links kept alive after a secret rotation once their grace
+    /// period has ended. Returns the number of links removed.
+    fn expire_retired_links(conn: &Connection, storage: &dyn Storage) -> Result<usize> {
+        let mut stmt = conn.prepare("SELECT share_hash FROM retired_links WHERE date_expires <= ?")?;
+        let overdue = stmt.query_map([Utc::now()], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for share_hash in &overdue {
+            storage.unpublish(share_hash)?;
+            conn.execute("DELETE FROM retired_links WHERE share_hash = ?", [share_hash])?;
+        }
+        Ok(overdue.len())
+    }
+
+    fn remove_retired_links(conn: &Connection, storage: &dyn Storage, uuid: &str, recipient: &str) -> Result<()> {
+        let mut stmt = conn.prepare(
+            "SELECT share_hash FROM retired_links WHERE uuid = ? AND recipient = ?"
+        )?;
+        let links = stmt.query_map([uuid, recipient], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for share_hash in &links {
+            storage.unpublish(share_hash)?;
+        }
+        conn.execute(
+            "DELETE FROM retired_links WHERE uuid = ? AND recipient = ?",
+            [uuid, recipient],
+        )?;
+        Ok(())
+    }
+
+    /// Recomputes the hash of every active share with `new_secret` and creates
+    /// the new links. `record` logs the moved shares in the same transaction.
+    /// The database is only committed once `save_secret` has
+    /// stored the new secret; if either fails, the database is left unchanged,
+    /// the new links are removed and `save_secret` is called again with the old
+    /// secret if needed. With `grace_until` the old hashes are recorded in
+    /// `retired_links` and keep working until then, otherwise the caller removes
+    /// them.
+    fn rotate_secret(
+        conn: &mut Connection,
+        config: &Config,
+        storage: &dyn Storage,
+        new_secret: &str,
+        grace_until: Option<DateTime<Utc>>,
+        record: impl FnOnce(&Connection, &[RotatedShare]) -> Result<()>,
+        save_secret: impl Fn(&str) -> Result<()>,
+    ) -> Result<Vec<RotatedShare>> {
+        let mut stmt = conn.prepare(
+            "SELECT s.uuid, s.recipient, s.share_hash, s.password_hash IS NOT NULL, f.encrypted, f.filename
+             FROM shares s JOIN files f ON f.uuid = s.uuid WHERE s.active = 1"
+        )?;
+        let active = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, bool>(3)?,
+                row.get::<_, bool>(4)?,
+                row.get::<_, String>(5)?,
+            ))
+        })?.collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        let mut rotated = Vec::new();
+        let published = (|| -> Result<()> {
+            for (uuid, recipient, old_hash, password_protected, encrypted, filename) in active {
+                let new_hash = calculate_share_hash(&uuid, &recipient, new_secret, config.hash_bytes)?;
+                storage.unpublish(&new_hash)?;
+                let published = if !password_protected {
+                    storage.publish(&new_hash, &uuid, &filename)
+                } else {
+                    Ok(())
+                };
+                // Recorded even on failure, so that a partly created link is removed too
+                rotated.push(RotatedShare { uuid, recipient, old_hash, new_hash, encrypted });
+                published?;
+            }
+            Ok(())
+        })();
+
+        let saved = published.and_then(|()| {
+            let now = Utc::now();
+            let tx = conn.transaction()?;
+            for share in &rotated {
+                tx.execute(
+                    "UPDATE shares SET share_hash = ? WHERE uuid = ? AND recipient = ?",
+                    params![share.new_hash, share.uuid, share.recipient],
+                )?;
+                if let Some(grace_until) = grace_until {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO retired_links
+                            (share_hash, uuid, recipient, date_retired, date_expires)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![share.old_hash, share.uuid, share.recipient, now, grace_until],
+                    )?;
+                }
+            }
+
+            record(&tx, &rotated)?;
+
+            // Dropping the transaction on error rolls the database back
+            save_secret(new_secret)?;
+            tx.commit().map_err(|e| match save_secret(&config.hash_secret) {
+                Ok(()) => anyhow!("Failed to update the database: {}", e),
+                Err(restore) => anyhow!("Failed to update the database: {}; the configuration now holds \
+                    a secret the links were not moved to and could not be restored: {}", e, restore),
+            })
+        });
+
+        if let Err(e) = saved {
+            for share in &rotated {
+                storage.unpublish(&share.new_hash)?;
+            }
+            return Err(e);
+        }
+        Ok(rotated)
+    }
+}
+
+impl RecipientGroup {
+    /// Accepts a group name with or without the leading `@`.
+    fn normalize_name(name: &str) -> Result<String> {
+        let name = name.trim().trim_start_matches('@');
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+            return Err(invalid!("Invalid group name: {}", name));
+        }
+        Ok(name.to_string())
+    }
+
+    fn find_all(conn: &Connection) -> Result<Vec<RecipientGroup>> {
+        let mut stmt = conn.prepare(
+            "SELECT group_name, recipient, owner FROM recipients ORDER BY group_name, recipient"
+        )?;
+        let rows = stmt.query_map([], |row| Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+        )))?.collect::<Result<Vec<_>, _>>()?;
+
+        let mut groups: Vec<RecipientGroup> = Vec::new();
+        for (name, recipient, owner) in rows {
+            match groups.last_mut() {
+                Some(group) if group.name == name => {
+                    group.members.push(recipient);
+                    group.owner = group.owner.take().or(owner);
+                }
+                _ => groups.push(RecipientGroup { name, members: vec![recipient], owner }),
+            }
+        }
+        Ok(groups)
+    }
+
+    fn find_by_name(conn: &Connection, name: &str) -> Result<Option<RecipientGroup>> {
+        let name = RecipientGroup::normalize_name(name)?;
+        Ok(RecipientGroup::find_all(conn)?.into_iter().find(|group| group.name == name))
+    }
+
+    /// Adds members as `owner`. Returns the number of recipients that were not
+    /// already members.
+    fn add_members(conn: &Connection, name: &str, recipients: &[String], owner: &str) -> Result<usize> {
+        let name = RecipientGroup::normalize_name(name)?;
+        let mut added = 0;
+        for recipient in recipients {
+            let recipient = recipient.trim();
+            if recipient.is_empty() || recipient.starts_with('@') {
+                return Err(invalid!("Invalid group member: {}", recipient));
+            }
+            added += conn.execute(
+                "INSERT OR IGNORE INTO recipients (group_name, recipient, date_added, owner) VALUES (?, ?, ?, ?)",
+                params![name, recipient, Utc::now(), owner],
+            )?;
+        }
+        Ok(added)
+    }
+
+    /// Removes the given members, or the whole group when `recipients` is empty.
+    /// Returns the number of members removed.
+    fn remove_members(conn: &Connection, name: &str, recipients: &[String]) -> Result<usize> {
+        let name = RecipientGroup::normalize_name(name)?;
+        if recipients.is_empty() {
+            return Ok(conn.execute("DELETE FROM recipients WHERE group_name = ?", [&name])?);
+        }
+
+        let mut removed = 0;
+        for recipient in recipients {
+            removed += conn.execute(
+                "DELETE FROM recipients WHERE group_name = ? AND recipient = ?",
+                params![name, recipient.trim()],
+            )?;
+        }
+        Ok(removed)
+    }
+
+    /// Expands `@group` to its members; any other recipient is returned as is.
+    fn expand(conn: &Connection, recipient: &str) -> Result<Vec<String>> {
+        if !recipient.starts_with('@') {
+            return Ok(vec![recipient.to_string()]);
+        }
+        RecipientGroup::find_by_name(conn, recipient)?
+            .map(|group| group.members)
+            .ok_or_else(|| invalid!("Unknown recipient group: {}", recipient))
+    }
+}
+
+impl ApiToken {
+    const COLUMNS: &'static str = "name, user, date_created, date_last_used";
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<ApiToken> {
+        Ok(ApiToken {
+            name: row.get(0)?,
+            user: row.get(1)?,
+            date_created: row.get(2)?,
+            date_last_used: row.get(3)?,
+        })
+    }
+
+    fn hash(secret: &str) -> String {
+        hex::encode(blake3::derive_key("slink api token", secret.as_bytes()))
+    }
+
+    /// Creates a token and returns it with its secret, which is not stored.
+    fn create(conn: &Connection, name: &str, user: &str) -> Result<(ApiToken, String)> {
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+            return Err(invalid!("Invalid token name: {}", name));
+        }
+        if ApiToken::find_by_name(conn, name)?.is_some() {
+            return Err(invalid!("Token {} already exists", name));
+        }
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let secret = format!("slink_{}", b64.encode(bytes));
+        conn.execute(
+            "INSERT INTO api_tokens (name, token_hash, user, date_created) VALUES (?1, ?2, ?3, ?4)",
+            params![name, ApiToken::hash(&secret), user, Utc::now()],
+        )?;
+
+        let token = ApiToken::find_by_name(conn, name)?.ok_or_else(|| invalid!("Token not found"))?;
+        Ok((token, secret))
+    }
+
+    /// Every token, or only those of `user`.
+    fn find_all(conn: &Connection, user: Option<&str>) -> Result<Vec<ApiToken>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_tokens WHERE ?1 IS NULL OR user = ?1 ORDER BY name",
+            ApiToken::COLUMNS
+        ))?;
+        let tokens = stmt.query_map([user], ApiToken::from_row)?;
+        tokens.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    fn find_by_name(conn: &Connection, name: &str) -> Result<Option<ApiToken>> {
+        conn.query_row(
+            &format!("SELECT {} FROM api_tokens WHERE name = ?", ApiToken::COLUMNS),
+            [name],
+            ApiToken::from_row,
+        ).optional().map_err(Into::into)
+    }
+
+    fn revoke(conn: &Connection, name: &str) -> Result<()> {
+        conn.execute("DELETE FROM api_tokens WHERE name = ?", [name])?;
+        Ok(())
+    }
+
+    /// Looks up the token with `secret` and records that it was used.
+    fn authenticate(conn: &Connection, secret: &str) -> Result<Option<ApiToken>> {
+        let token_hash = ApiToken::hash(secret);
+        conn.execute(
+            "UPDATE api_tokens SET date_last_used = ?1 WHERE token_hash = ?2",
+            params![Utc::now(), token_hash],
+        )?;
+        conn.query_row(
+            &format!("SELECT {} FROM api_tokens WHERE token_hash = ?", ApiToken::COLUMNS),
+            [token_hash],
+            ApiToken::from_row,
+        ).optional().map_err(Into::into)
+    }
+}
+
+impl UploadRequest {
+    const COLUMNS: &'static str = "r.request_hash, r.recipient, r.name_hint, r.owner, r.date_created,
+        r.date_expires, r.active, (SELECT COUNT(*) FROM files f WHERE f.request_hash = r.request_hash)";
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<UploadRequest> {
+        Ok(UploadRequest {
+            request_hash: row.get(0)?,
+            recipient: row.get(1)?,
+            name_hint: row.get(2)?,
+            owner: row.get(3)?,
+            date_created: row.get(4)?,
+            date_expires: row.get(5)?,
+            active: row.get(6)?,
+            uploads: row.get(7)?,
+        })
+    }
+
+    /// Whether uploads are still accepted.
+    pub fn is_open(&self) -> bool {
+        self.active && self.date_expires.is_none_or(|expires| expires > Utc::now())
+    }
+
+    fn create(
+        conn: &Connection,
+        config: &Config,
+        recipient: &str,
+        name_hint: Option<&str>,
+        expires: Option<DateTime<Utc>>,
+        owner: &str,
+    ) -> Result<UploadRequest> {
+        // Keyed like share links, over a random ID so every request gets a new link
+        let request_hash = calculate_share_hash(
+            &uuid::Uuid::new_v4().to_string(), recipient, &config.hash_secret, config.hash_bytes)?;
+        conn.execute(
+            "INSERT INTO upload_requests (request_hash, recipient, name_hint, owner, date_created, date_expires)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![request_hash, recipient, name_hint, owner, Utc::now(), expires],
+        )?;
+        UploadRequest::find_by_hash(conn, &request_hash)?
+            .ok_or_else(|| invalid!("Upload request not found"))
+    }
+
+    /// Every request, or only those of `owner`, newest first.
+    fn find_all(conn: &Connection, owner: Option<&str>) -> Result<Vec<UploadRequest>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM upload_requests r WHERE ?1 IS NULL OR r.owner = ?1 ORDER BY r.date_created DESC",
+            UploadRequest::COLUMNS
+        ))?;
+        let requests = stmt.query_map([owner], UploadRequest::from_row)?;
+        requests.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    fn find_by_hash(conn: &Connection, request_hash: &str) -> Result<Option<UploadRequest>> {
+        conn.query_row(
+            &format!("SELECT {} FROM upload_requests r WHERE r.request_hash = ?", UploadRequest::COLUMNS),
+            [request_hash],
+            UploadRequest::from_row,
+        ).optional().map_err(Into::into)
+    }
+
+    fn close(conn: &Connection, request_hash: &str) -> Result<()> {
+        conn.execute("UPDATE upload_requests SET active = 0 WHERE request_hash = ?", [request_hash])?;
+        Ok(())
+    }
+}
+
+impl Download {
+    /// Stores the download against the share it belongs to. Returns `false` if
+    /// the hash matches no share or the same log line was already recorded.
+    fn record(&self, conn: &Connection) -> Result<bool> {
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO downloads
+                (uuid, recipient, share_hash, date_accessed, ip, user_agent, status, bytes, log_entry)
+             SELECT uuid, recipient, ?1, ?2, ?3, ?4, ?5, ?6, ?7
+             FROM (SELECT uuid, recipient FROM shares WHERE share_hash = ?1
+                   UNION ALL
+                   SELECT uuid, recipient FROM retired_links WHERE share_hash = ?1)
+             LIMIT 1",
+            params![
+                self.share_hash,
+                self.date_accessed,
+                self.ip,
+                self.user_agent,
+                self.status,
+                self.bytes as i64,
+                self.log_entry
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Records a full download of a share with a download limit before any of
+    /// it is sent, so that concurrent requests cannot exceed the limit. Returns
+    /// the row to `complete` or `release`, or `None` if the limit is reached.
+    fn reserve(&self, conn: &Connection) -> Result<Option<i64>> {
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        let remaining = tx.query_row(
+            "SELECT s.max_downloads - (SELECT COUNT(*) FROM downloads d
+                                       WHERE d.uuid = s.uuid AND d.recipient = s.recipient
+                                         AND d.status = 200)
+             FROM shares s WHERE s.share_hash = ? AND s.active = 1",
+            [&self.share_hash],
+            |row| row.get::<_, Option<i64>>(0),
+        ).optional()?.flatten();
+
+        if remaining.is_some_and(|remaining| remaining <= 0) || !self.record(&tx)? {
+            return Ok(None);
+        }
+        let id = tx.last_insert_rowid();
+        tx.commit()?;
+        Ok(Some(id))
+    }
+
+    /// Fills in the bytes sent for a download recorded by `reserve`.
+    fn complete(conn: &Connection, id: i64, bytes: u64) -> Result<()> {
+        conn.execute("UPDATE downloads SET bytes = ? WHERE rowid = ?", params![bytes as i64, id])?;
+        Ok(())
+    }
+
+    /// Gives back a download recorded by `reserve` that could not be sent.
+    fn release(conn: &Connection, id: i64) -> Result<()> {
+        conn.execute("DELETE FROM downloads WHERE rowid = ?", [id])?;
+        Ok(())
+    }
+}
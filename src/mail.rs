@@ -0,0 +1,202 @@
+// src/mail.rs
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use lettre::message::{header::ContentType, Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SendmailTransport, SmtpTransport, Transport};
+use crate::MailConfig;
+
+const DEFAULT_SUBJECT: &str = "{sender} shared {filename} with you";
+
+const DEFAULT_BODY: &str = "Hello,
+
+{sender} has shared a file with you:
+
+  {filename} ({size})
+
+Download it here:
+
+  {url}
+
+{restrictions}
+";
+
+/// What a share notification tells the recipient about.
+pub struct ShareNotification<'a> {
+    pub recipient: &'a str,
+    pub filename: &'a str,
+    pub size: Option<String>,
+    pub url: &'a str,
+    pub expires: Option<DateTime<Utc>>,
+    pub max_downloads: Option<u32>,
+    pub password_protected: bool,
+}
+
+/// Checks the `[mail]` section before any share is created, so that a broken
+/// configuration does not leave shares behind without notifications.
+pub fn validate(mail: &MailConfig) -> Result<()> {
+    mail.from.parse::<Mailbox>()
+        .map_err(|e| anyhow!("Invalid mail.from address {}: {}", mail.from, e))?;
+
+    match (&mail.smtp_host, &mail.sendmail_path) {
+        (Some(_), Some(_)) => Err(anyhow!("Set either mail.smtp_host or mail.sendmail_path, not both")),
+        (None, None) => Err(anyhow!("Set mail.smtp_host or mail.sendmail_path to send notifications")),
+        _ => Ok(()),
+    }
+}
+
+pub fn send_share_notification(mail: &MailConfig, notification: &ShareNotification) -> Result<()> {
+    let message = build_message(mail, notification)?;
+
+    if let Some(sendmail) = &mail.sendmail_path {
+        SendmailTransport::new_with_command(sendmail).send(&message)?;
+        return Ok(());
+    }
+
+    let host = mail.smtp_host.as_deref()
+        .ok_or_else(|| anyhow!("mail.smtp_host is not set"))?;
+    let mut builder = match mail.smtp_security.as_deref().unwrap_or("starttls") {
+        "starttls" => SmtpTransport::starttls_relay(host)?,
+        "tls" => SmtpTransport::relay(host)?,
+        "none" => SmtpTransport::builder_dangerous(host),
+        other => return Err(anyhow!("Unknown mail.smtp_security {} (use starttls, tls or none)", other)),
+    };
+    if let Some(port) = mail.smtp_port {
+        builder = builder.port(port);
+    }
+    if let (Some(username), Some(password)) = (&mail.smtp_username, &mail.smtp_password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    builder.build().send(&message)?;
+    Ok(())
+}
+
+fn build_message(mail: &MailConfig, notification: &ShareNotification) -> Result<Message> {
+    let to: Mailbox = notification.recipient.parse()
+        .map_err(|_| anyhow!("{} is not an email address", notification.recipient))?;
+    let from: Mailbox = mail.from.parse()?;
+
+    let sender = from.name.clone().unwrap_or_else(|| from.email.to_string());
+    let subject = render(mail.subject.as_deref().unwrap_or(DEFAULT_SUBJECT), &sender, notification);
+    let body = render(mail.body.as_deref().unwrap_or(DEFAULT_BODY), &sender, notification);
+
+    Ok(Message::builder()
+        .from(from)
+        .to(to)
+        .subject(subject.trim())
+        .header(ContentType::TEXT_PLAIN)
+        .body(body)?)
+}
+
+/// Replaces `{placeholder}`s in a subject or body template. Unknown
+/// placeholders are left as they are.
+fn render(template: &str, sender: &str, notification: &ShareNotification) -> String {
+    let mut restrictions = Vec::new();
+    if let Some(expires) = notification.expires {
+        restrictions.push(format!("The link expires on {} UTC.", expires.format("%Y-%m-%d %H:%M")));
+    }
+    if let Some(max_downloads) = notification.max_downloads {
+        restrictions.push(format!("It can be downloaded {} time(s).", max_downloads));
+    }
+    if notification.password_protected {
+        restrictions.push("You will be asked for a password, which is sent separately.".to_string());
+    }
+
+    template
+        .replace("{sender}", sender)
+        .replace("{recipient}", notification.recipient)
+        .replace("{filename}", notification.filename)
+        .replace("{size}", notification.size.as_deref().unwrap_or("unknown size"))
+        .replace("{url}", notification.url)
+        .replace("{expires}", &notification.expires
+            .map_or("never".to_string(), |e| e.format("%Y-%m-%d %H:%M UTC").to_string()))
+        .replace("{restrictions}", &restrictions.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn mail_config() -> MailConfig {
+        MailConfig {
+            from: "Files <files@example.com>".to_string(),
+            smtp_host: Some("localhost".to_string()),
+            smtp_port: None,
+            smtp_security: None,
+            smtp_username: None,
+            smtp_password: None,
+            sendmail_path: None,
+            subject: None,
+            body: None,
+        }
+    }
+
+    fn notification() -> ShareNotification<'static> {
+        ShareNotification {
+            recipient: "alice@example.com",
+            filename: "report.pdf",
+            size: Some("1.2 MiB".to_string()),
+            url: "https://example.com/KJh8h7G6dT/report.pdf",
+            expires: None,
+            max_downloads: None,
+            password_protected: false,
+        }
+    }
+
+    fn formatted(message: &Message) -> String {
+        String::from_utf8(message.formatted()).unwrap()
+    }
+
+    #[test]
+    fn default_templates() {
+        let message = formatted(&build_message(&mail_config(), &notification()).unwrap());
+        assert!(message.contains("From: Files <files@example.com>"));
+        assert!(message.contains("To: alice@example.com"));
+        assert!(message.contains("Subject: Files shared report.pdf with you"));
+        assert!(message.contains("report.pdf (1.2 MiB)"));
+        assert!(message.contains("https://example.com/KJh8h7G6dT/report.pdf"));
+    }
+
+    #[test]
+    fn restrictions_are_listed() {
+        let notification = ShareNotification {
+            expires: Some(Utc.with_ymd_and_hms(2025, 2, 1, 12, 30, 0).unwrap()),
+            max_downloads: Some(1),
+            password_protected: true,
+            ..notification()
+        };
+        let body = render("{restrictions}", "Files", &notification);
+        assert_eq!(body, "The link expires on 2025-02-01 12:30 UTC. It can be downloaded 1 time(s). \
+            You will be asked for a password, which is sent separately.");
+    }
+
+    #[test]
+    fn custom_templates() {
+        let mail = MailConfig {
+            subject: Some("[{sender}] {filename} for {recipient}".to_string()),
+            body: Some("{url} until {expires} {unknown}".to_string()),
+            ..mail_config()
+        };
+        let message = formatted(&build_message(&mail, &notification()).unwrap());
+        assert!(message.contains("Subject: [Files] report.pdf for alice@example.com"));
+        assert!(message.contains("https://example.com/KJh8h7G6dT/report.pdf until never {unknown}"));
+    }
+
+    #[test]
+    fn recipient_must_be_an_address() {
+        let notification = ShareNotification { recipient: "alice", ..notification() };
+        let error = build_message(&mail_config(), &notification).unwrap_err();
+        assert_eq!(error.to_string(), "alice is not an email address");
+    }
+
+    #[test]
+    fn validate_requires_one_transport() {
+        assert!(validate(&mail_config()).is_ok());
+        let both = MailConfig { sendmail_path: Some("/usr/sbin/sendmail".to_string()), ..mail_config() };
+        assert!(validate(&both).is_err());
+        let neither = MailConfig { smtp_host: None, ..mail_config() };
+        assert!(validate(&neither).is_err());
+    }
+}
@@ -1,106 +1,108 @@
 // src/main.rs
-mod commands;
-use chrono::{DateTime, Utc};
-use dirs::config_dir;
-use rusqlite::{params, Connection};
-use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64, Engine as _};
-use serde::{Deserialize, Serialize};
-use std::os::unix::fs::PermissionsExt;
-use std::{
-    fs::{self, create_dir_all, remove_dir_all, set_permissions, Permissions},
-    os::unix::fs::symlink as unix_symlink,
-    path::{Path, PathBuf},
-};
-use nix::unistd::chown;
-use nix::unistd::{Gid, Uid};
-
-use structopt::StructOpt;
-use uuid::Uuid;
-
 use anyhow::{anyhow, Result};
-use std::io::{self, Write};
-
-/*
-slink is a self-hosted file sharing  utility written in Rust that enables secure
-file sharing through unique URLs. The program  manages files on a web server and
-creates secure, recipient-specific sharing links.
-
-Core functionality:
-- Files are stored with UUIDs in a base directory (e.g., /var/www/UUID/filename)
-- Sharing links are created using keyed BLAKE3 of UUID + recipient identifier
-- File and share information is tracked in SQLite
-- Configuration stored in ~/.config/slink/slink.conf (TOML format)
-- Runs on the server side, managing files directly
-
-Command interface:
-- add: Copy file to managed directory with UUID
-- share: Create recipient-specific sharing link
-- unshare: Remove sharing link but retain history
-- show: Display file info and share status
-- ls: List all managed files
-- rm: Remove file and its shares
-
-File structure:
-- Original file: BASE_DIR/UUID/filename
-- Share links: BASE_DIR/HASH -> UUID (relative symlink)
-
-URL format:
-- Private: https://domain/UUID/filename
-- Shared: https://domain/HASH/filename
-
-Security considerations:
-- Runs as dedicated user with appropriate permissions
-- Web server must follow symlinks
-- BLAKE3 secret stored in config
-- Share history maintained in SQLite
+use slink::{api, parse_past_time, serve, Config, ListFilter, ListSort, RemoteConfig, Store};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use commands::Encryption;
 
-Database schema:
-- files: uuid, filename, date_added
-- shares: uuid, recipient, share_hash, date_shared, date_removed, active
+mod commands;
+mod remote;
 
-Configuration (slink.conf):
-- base_url: Web server URL
-- base_dir: File storage location
-- db_path: SQLite database path
-- hash_secret: Secret for hash generation
-- web_user: Owner of files
-- web_group: Group for web access
-- hash_bytes: Length of resulting hash before base64 encoding
+#[derive(Debug, StructOpt)]
+#[structopt(name = "slink", about = "Secure file sharing utility")]
+struct Opt {
+    /// Output format for add, show, ls, info, group ls, token ls, request --list and audit
+    #[structopt(long = "format", global = true, default_value = "table",
+        possible_values = &["table", "json", "csv"])]
+    format: OutputFormat,
+    /// Configuration file to use instead of the default (also SLINK_CONFIG)
+    #[structopt(long = "config", global = true, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Apply the [profiles.NAME] section of the configuration (also SLINK_PROFILE)
+    #[structopt(long = "profile", global = true)]
+    profile: Option<String>,
+    #[structopt(subcommand)]
+    command: Command,
+}
 
-The program is  designed to be simple, secure, and  maintainable, following Unix
-philosophy of doing one thing well.  It integrates with existing web servers and
-provides a straightforward CLI for file sharing management.
-*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
 
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    base_url: String,
-    base_dir: String,
-    db_path: String,
-    hash_secret: String,
-    web_user: String,
-    web_group: String,
-    hash_bytes: usize,
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(anyhow!("Unknown output format: {}", s)),
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "slink", about = "Secure file sharing utility")]
-enum Opt {
+enum Command {
     #[structopt(name = "init")]
-    Init,
+    Init {
+        /// Create the system-wide configuration in /etc/slink for several operators
+        #[structopt(long = "system")]
+        system: bool,
+        #[structopt(flatten)]
+        init_flags: InitFlags,
+    },
     #[structopt(name = "add")]
     Add {
-        file: String,
+        /// Files, directories (with -r) or glob patterns to add, or - for stdin
+        #[structopt(required = true)]
+        files: Vec<String>,
+        /// Add every file below the given directories, each under its own UUID
+        #[structopt(short = "r", long = "recursive")]
+        recursive: bool,
         #[structopt(short = "n", long = "name")]
         name: Option<String>,
         #[structopt(short = "s", long = "share")]
         share: Option<String>,
+        /// Return the UUID of an identical stored file instead of adding a new one
+        #[structopt(long = "dedupe")]
+        dedupe: bool,
+        /// stdin is already encrypted; used by `add --encrypt` in remote mode
+        #[structopt(long = "encrypted", hidden = true, conflicts_with = "encrypt")]
+        encrypted: bool,
+        #[structopt(flatten)]
+        share_flags: ShareFlags,
     },
     #[structopt(name = "share")]
     Share {
         recipient: String,
         file: String,
+        #[structopt(flatten)]
+        share_flags: ShareFlags,
+    },
+    /// Create a link through which someone can upload files to you via `slink serve`
+    #[structopt(name = "request")]
+    Request {
+        /// Who will upload, e.g. partner@example.com
+        #[structopt(required_unless_one = &["list", "close"])]
+        recipient: Option<String>,
+        /// File name to use when the upload does not name the file
+        #[structopt(short = "n", long = "name")]
+        name: Option<String>,
+        /// Expiry as a duration (30m, 12h, 7d, 2w) or a UTC timestamp
+        #[structopt(short = "e", long = "expires")]
+        expires: Option<String>,
+        /// List your upload requests instead, or everyone's with --all
+        #[structopt(long = "list", conflicts_with_all = &["recipient", "close"])]
+        list: bool,
+        #[structopt(short = "a", long = "all", requires = "list")]
+        all: bool,
+        /// Stop accepting uploads through the request with this hash
+        #[structopt(long = "close", conflicts_with = "recipient")]
+        close: Option<String>,
     },
     #[structopt(name = "unshare")]
     Unshare {
@@ -112,7 +114,47 @@ enum Opt {
         file: String,
     },
     #[structopt(name = "ls")]
-    List,
+    List {
+        /// Include files owned by other users
+        #[structopt(short = "a", long = "all")]
+        all: bool,
+        /// Only files shared with this recipient, now or in the past
+        #[structopt(short = "r", long = "recipient")]
+        recipient: Option<String>,
+        /// Only files added since this time (e.g. 7d or 2025-01-31)
+        #[structopt(long = "since")]
+        since: Option<String>,
+        /// Only files added before this time (e.g. 30d or 2025-01-31)
+        #[structopt(long = "until")]
+        until: Option<String>,
+        /// Only files with active shares
+        #[structopt(long = "active-only", conflicts_with = "no-shares")]
+        active_only: bool,
+        /// Only files without active shares
+        #[structopt(long = "no-shares")]
+        no_shares: bool,
+        /// Only files whose name matches this glob, ignoring case (e.g. '*.pdf')
+        #[structopt(short = "n", long = "name")]
+        name: Option<String>,
+        /// Sort by date, name, size or shares
+        #[structopt(short = "s", long = "sort", default_value = "date")]
+        sort: ListSort,
+        /// Reverse the sort order
+        #[structopt(long = "reverse")]
+        reverse: bool,
+        /// Show at most this many files
+        #[structopt(long = "limit")]
+        limit: Option<u64>,
+        /// Skip this many files first
+        #[structopt(long = "offset", default_value = "0")]
+        offset: u64,
+    },
+    /// Hand a file over to another user (admins only)
+    #[structopt(name = "chown")]
+    Chown {
+        owner: String,
+        file: String,
+    },
     #[structopt(name = "rm")]
     Remove {
         file: String,
@@ -121,343 +163,359 @@ enum Opt {
     },
     #[structopt(name = "info")]
     Info,
+    #[structopt(name = "expire")]
+    Expire,
+    #[structopt(name = "serve")]
+    Serve {
+        #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8080")]
+        listen: String,
+        #[structopt(long = "cert", requires = "key")]
+        cert: Option<String>,
+        #[structopt(long = "key", requires = "cert")]
+        key: Option<String>,
+        #[structopt(long = "threads", default_value = "8")]
+        threads: usize,
+        /// Largest file accepted through an upload link, in MiB
+        #[structopt(long = "max-upload", default_value = "1024")]
+        max_upload: u64,
+    },
+    /// Serve the REST API for managing files and shares, see `slink token`
+    #[structopt(name = "api")]
+    Api {
+        #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8081")]
+        listen: String,
+        #[structopt(long = "cert", requires = "key")]
+        cert: Option<String>,
+        #[structopt(long = "key", requires = "cert")]
+        key: Option<String>,
+        #[structopt(long = "threads", default_value = "4")]
+        threads: usize,
+        /// Largest file accepted by POST /api/v1/files, in MiB
+        #[structopt(long = "max-upload", default_value = "1024")]
+        max_upload: u64,
+    },
+    /// Manage tokens for the REST API
+    #[structopt(name = "token")]
+    Token(TokenCommand),
+    #[structopt(name = "ingest-log")]
+    IngestLog {
+        path: String,
+    },
+    #[structopt(name = "db")]
+    Db(DbCommand),
+    /// Download an encrypted share and decrypt it with the key in the link
+    #[structopt(name = "fetch")]
+    Fetch {
+        /// The full link, including the #key fragment
+        url: String,
+        /// Where to save the file; defaults to its name in the link
+        #[structopt(short = "o", long = "output")]
+        output: Option<String>,
+    },
+    #[structopt(name = "group")]
+    Group(GroupCommand),
+    /// List the audit log of every change, or verify its hash chain
+    #[structopt(name = "audit")]
+    Audit {
+        #[structopt(subcommand)]
+        command: Option<AuditCommand>,
+        /// Only entries about this file (name or UUID; UUID for removed files)
+        #[structopt(long = "file")]
+        file: Option<String>,
+        /// Only entries about this share link hash
+        #[structopt(long = "hash")]
+        hash: Option<String>,
+    },
+    #[structopt(name = "verify")]
+    Verify {
+        file: Option<String>,
+    },
+    #[structopt(name = "fsck")]
+    Fsck {
+        /// Fix the problems found instead of only reporting them
+        #[structopt(long = "repair")]
+        repair: bool,
+    },
+    #[structopt(name = "rotate-secret")]
+    RotateSecret {
+        /// Keep old links working for this long (e.g. 7d) instead of removing them
+        #[structopt(short = "g", long = "grace")]
+        grace: Option<String>,
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+    },
 }
 
-struct FileShare {
-    uuid: String,
-    filename: String,
-    date_added: DateTime<Utc>,
-}
-
-struct ShareInfo {
-    recipient: String,
-    share_hash: String,
-    date_shared: DateTime<Utc>,
-    date_removed: Option<DateTime<Utc>>,
-    active: bool,
+#[derive(Debug, StructOpt)]
+enum DbCommand {
+    #[structopt(name = "migrate")]
+    Migrate,
+    #[structopt(name = "status")]
+    Status,
 }
 
-impl Config {
-     fn check_permissions(config_path: &Path) -> Result<()> {
-        let metadata = fs::metadata(config_path)?;
-        let mode = metadata.permissions().mode();
-
-        // Check if file is readable by group or others
-        if mode & 0o077 != 0 {
-            return Err(anyhow!("Config file permissions too loose. Use chmod 600 {}",
-                config_path.display()));
-        }
-        Ok(())
-    }
-
-    fn load_or_create() -> Result<Self> {
-        let config_path = config_dir()
-            .ok_or_else(|| anyhow!("Could not determine config directory"))?
-            .join("slink")
-            .join("slink.conf");
-
-        if !config_path.exists() {
-            return Err(anyhow!(
-                "Configuration file not found. Please run `slink init` to create one."
-            ));
-        }
-
-        // Check config file permissions
-        if let Err(e) = Config::check_permissions(&config_path) {
-            eprintln!("WARNING: {}", e);
-        }
-
-        let content = fs::read_to_string(&config_path)
-            .map_err(|e| anyhow!("Failed to read config file {}: {}", config_path.display(), e))?;
-        let config: Config = toml::from_str(&content)?;
-
-        if !Path::new(&config.db_path).join("shares.db").exists() {
-            // Try to initialize database
-            init_database(&config.db_path)
-                .map_err(|e| anyhow!("Failed to initialize database {}: {}", config.db_path, e))?;
-        }
-        Ok(config)
-    }
-
-
+#[derive(Debug, StructOpt)]
+enum GroupCommand {
+    /// Add recipients to a group, creating it if needed
+    #[structopt(name = "add")]
+    Add {
+        group: String,
+        #[structopt(required = true)]
+        recipients: Vec<String>,
+    },
+    /// Remove recipients from a group, or the whole group if none are given
+    #[structopt(name = "rm")]
+    Remove {
+        group: String,
+        recipients: Vec<String>,
+    },
+    #[structopt(name = "ls")]
+    List {
+        group: Option<String>,
+    },
 }
 
-fn init_database(db_path: &str) -> Result<()> {
-    let conn = Connection::open(db_path)?;
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS files (
-            uuid CHAR(36) NOT NULL PRIMARY KEY,
-            filename TEXT NOT NULL,
-            date_added DATETIME NOT NULL
-        )",
-        [],
-    )?;
-
-    conn.execute(
-       "CREATE TABLE IF NOT EXISTS shares (
-            uuid CHAR(36) NOT NULL,
-            recipient TEXT NOT NULL,
-            share_hash TEXT NOT NULL,
-            date_shared DATETIME NOT NULL,
-            date_removed DATETIME,
-            active BOOLEAN NOT NULL DEFAULT 1,
-            PRIMARY KEY (uuid, recipient),
-            FOREIGN KEY (uuid) REFERENCES files(uuid)
-        )",
-        [],
-    )?;
-
-    Ok(())
+#[derive(Debug, StructOpt)]
+enum TokenCommand {
+    /// Create a token; it is printed once and cannot be shown again
+    #[structopt(name = "create")]
+    Create {
+        name: String,
+        /// User the token acts as (admins only; default: yourself)
+        #[structopt(long = "user")]
+        user: Option<String>,
+    },
+    #[structopt(name = "ls")]
+    List,
+    #[structopt(name = "rm")]
+    Remove {
+        name: String,
+    },
 }
 
-fn calculate_share_hash(uuid: &str, recipient: &str, secret: &str, hash_bytes: usize) -> Result<String> {
-    let key = blake3::derive_key("slink", secret.as_bytes());
-    let keyed_hash = blake3::keyed_hash(
-        &key,
-        format!("{}{}", uuid, recipient).as_bytes(),
-    );
-
-    Ok(b64.encode(&keyed_hash.as_bytes()[..hash_bytes]))
+#[derive(Debug, StructOpt)]
+enum AuditCommand {
+    /// Check that no entry was modified, removed or reordered
+    #[structopt(name = "verify")]
+    Verify,
 }
 
-fn set_permissions_recursive(
-    path: &Path,
-    dir_mode: u32,
-    file_mode: u32,
-    web_user: &str,
-    web_group: &str,
-) -> Result<()> {
-    // Resolve the user and group IDs
-    let web_uid = users::get_user_by_name(web_user)
-        .ok_or_else(|| anyhow::anyhow!("User {} not found", web_user))?
-        .uid();
-    let web_gid = users::get_group_by_name(web_group)
-        .ok_or_else(|| anyhow::anyhow!("Group {} not found", web_group))?
-        .gid();
-
-    // Get current user's UID and primary GID
-    let current_uid = nix::unistd::getuid();
-    let current_gid = nix::unistd::getgid();
-
-    if path.is_dir() {
-        // Set directory permissions to allow owner access first
-        set_permissions(path, Permissions::from_mode(0o700))?;
-
-        // Change ownership to current user temporarily
-        chown(path, Some(current_uid), Some(current_gid))?;
-
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            set_permissions_recursive(&entry.path(), dir_mode, file_mode, web_user, web_group)?;
-        }
-
-        // Now set final permissions and ownership
-        set_permissions(path, Permissions::from_mode(dir_mode))?;
-        chown(path, Some(Uid::from_raw(web_uid)), Some(Gid::from_raw(web_gid)))?;
-    } else {
-        // For files, temporarily make them fully accessible to owner
-        set_permissions(path, Permissions::from_mode(0o600))?;
-        chown(path, Some(current_uid), Some(current_gid))?;
-
-        // Set final permissions and ownership
-        set_permissions(path, Permissions::from_mode(file_mode))?;
-        chown(path, Some(Uid::from_raw(web_uid)), Some(Gid::from_raw(web_gid)))?;
-    }
-    Ok(())
+/// Answers for `init`. Values given here are validated like typed ones and
+/// not asked for; with --yes the defaults are used for the rest.
+#[derive(Debug, StructOpt)]
+struct InitFlags {
+    #[structopt(long = "base-url")]
+    base_url: Option<String>,
+    /// Existing directory the files are stored in
+    #[structopt(long = "base-dir")]
+    base_dir: Option<String>,
+    #[structopt(long = "db-path")]
+    db_path: Option<String>,
+    #[structopt(long = "web-user")]
+    web_user: Option<String>,
+    #[structopt(long = "web-group")]
+    web_group: Option<String>,
+    /// Length of share hashes in bytes (2-32)
+    #[structopt(long = "hash-bytes")]
+    hash_bytes: Option<String>,
+    /// Read the hash secret from this file instead of generating one
+    #[structopt(long = "secret-file", parse(from_os_str))]
+    secret_file: Option<PathBuf>,
+    /// Group whose members may manage every file (with --system)
+    #[structopt(long = "admin-group", requires = "system")]
+    admin_group: Option<String>,
+    /// Accept the default for every value not given as a flag
+    #[structopt(short = "y", long = "yes")]
+    yes: bool,
 }
 
-fn remove_file_with_access(path: &Path) -> Result<()> {
-    // Get current user's UID and GID
-    let current_uid = nix::unistd::getuid();
-    let current_gid = nix::unistd::getgid();
-
-    // Temporarily take ownership and full permissions
-    chown(path, Some(current_uid), Some(current_gid))?;
-    set_permissions(path, Permissions::from_mode(0o700))?;
-
-    if path.is_dir() {
-        remove_dir_all(path)
-    } else {
-        fs::remove_file(path)
-    }.map_err(Into::into)
+/// Share settings accepted by both `share` and `add -s`.
+#[derive(Debug, StructOpt)]
+struct ShareFlags {
+    /// Expiry as a duration (30m, 12h, 7d, 2w) or a UTC timestamp
+    #[structopt(short = "e", long = "expires")]
+    expires: Option<String>,
+    /// Revoke the share after this many downloads
+    #[structopt(long = "max-downloads")]
+    max_downloads: Option<u32>,
+    /// Prompt for a password the recipient must enter to download
+    #[structopt(short = "p", long = "password", conflicts_with = "generate-password")]
+    password: bool,
+    /// Protect the share with a generated passphrase
+    #[structopt(long = "generate-password")]
+    generate_password: bool,
+    /// Email the link to the recipient using the [mail] configuration
+    #[structopt(long = "notify")]
+    notify: bool,
+    /// With add: encrypt the file before it is stored, printing the key once.
+    /// With share: fail unless the file was added that way
+    #[structopt(long = "encrypt", conflicts_with_all = &["password", "generate-password"])]
+    encrypt: bool,
 }
 
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
 
-impl FileShare {
-    fn find_by_name(conn: &Connection, filename: &str) -> Result<Vec<(String, DateTime<Utc>)>> {
-        let mut stmt = conn.prepare(
-            "SELECT uuid, date_added FROM files WHERE filename = ? ORDER BY date_added"
-        )?;
-
-        let results = stmt.query_map([filename], |row| {
-            Ok((row.get(0)?, row.get(1)?))
-        })?;
-
-        results.collect::<Result<Vec<_>, _>>().map_err(Into::into)
-    }
-
-    fn find_by_uuid(conn: &Connection, uuid: &str) -> Result<Option<FileShare>> {
-        let mut stmt = conn.prepare(
-            "SELECT filename, date_added FROM files WHERE uuid = ?"
-        )?;
-
-        let mut rows = stmt.query([uuid])?;
-
-        if let Some(row) = rows.next()? {
-            Ok(Some(FileShare {
-                uuid: uuid.to_string(),
-                filename: row.get(0)?,
-                date_added: row.get(1)?,
-            }))
-        } else {
-            Ok(None)
+    // Handle `slink init` command separately
+    if let Command::Init { system, init_flags } = &opt.command {
+        if opt.profile.is_some() || std::env::var_os("SLINK_PROFILE").is_some() {
+            return Err(anyhow!("slink init creates a configuration without profiles; \
+                add [profiles.NAME] sections to it afterwards"));
         }
+        commands::initialize_config(Config::explicit_path(opt.config.as_deref()), *system, init_flags)?;
+        return Ok(());
     }
 
-    fn remove(&self, conn: &Connection, config: &Config, force: bool) -> Result<()> {
-    if !force {
-        print!("Are you sure you want to remove {}? [y/N] ", self.filename);
-        io::stdout().flush()?;
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        if !input.trim().eq_ignore_ascii_case("y") {
-            return Ok(());
-        }
+    // `slink fetch` runs on the recipient's side and needs no configuration
+    if let Command::Fetch { url, output } = &opt.command {
+        return commands::fetch(url, output.as_deref());
     }
 
-    // Remove all symlinks
-    let shares_dir = PathBuf::from(&config.base_dir);
-    for entry in fs::read_dir(&shares_dir)? {
-        let entry = entry?;
-        if let Ok(target) = fs::read_link(entry.path()) {
-            if target.ends_with(&self.uuid) {
-                remove_file_with_access(&entry.path())?;
+    // For all other commands, load the configuration
+    let config_path = Config::resolve_path(opt.config.as_deref())?;
+    let profile = opt.profile.or_else(|| std::env::var("SLINK_PROFILE").ok());
+
+    // With a [remote] section the server does the work
+    if let Some(remote) = RemoteConfig::load(&config_path, profile.as_deref())? {
+        return match &opt.command {
+            Command::Add { files, recursive, name, share, dedupe, encrypted: false, share_flags } => {
+                remote::add_files(&remote, files, *recursive, name.as_deref(), *dedupe,
+                    share.as_deref(), share_flags, opt.format)
             }
-        }
-    }
-
-    // Remove the file directory
-    let file_dir = PathBuf::from(&config.base_dir).join(&self.uuid);
-    remove_file_with_access(&file_dir)?;
-
-    // Update database
-    conn.execute(
-        "UPDATE shares SET active = 0, date_removed = ? WHERE uuid = ?",
-        params![Utc::now(), self.uuid],
-    )?;
-
-    conn.execute("DELETE FROM files WHERE uuid = ?", [&self.uuid])?;
-
-    Ok(())
-}
-
-}
-
-impl ShareInfo {
-    fn share(conn: &Connection, config: &Config, uuid: &str, recipient: &str) -> Result<String> {
-        let share_hash = calculate_share_hash(uuid, recipient, &config.hash_secret, config.hash_bytes)?;
-
-        // Create symlink with relative path
-        let source = PathBuf::from(&config.base_dir).join(&share_hash);
-        // Remove existing symlink if it exists
-        if source.exists() {
-            fs::remove_file(&source)?;
-        }
-        unix_symlink(uuid, source)?;
-
-        // Use REPLACE INTO or INSERT OR REPLACE to handle existing shares
-        conn.execute(
-            "INSERT OR REPLACE INTO shares (uuid, recipient, share_hash, date_shared, active)
-             VALUES (?1, ?2, ?3, ?4, 1)",
-            params![uuid, recipient, share_hash, Utc::now()],
-        )?;
-
-        Ok(share_hash)
+            _ => remote::forward(&remote),
+        };
     }
 
-
-    fn unshare(conn: &Connection, config: &Config, uuid: &str, recipient: &str) -> Result<()> {
-        let share_hash = calculate_share_hash(uuid, recipient, &config.hash_secret, config.hash_bytes)?;
-
-        // Remove symlink
-        let symlink = PathBuf::from(&config.base_dir).join(&share_hash);
-        if symlink.exists() {
-            fs::remove_file(symlink)?;
+    if let Err(e) = Config::check_permissions(&config_path) {
+        if config_path.exists() {
+            eprintln!("WARNING: {}", e);
         }
-
-        conn.execute(
-            "UPDATE shares SET active = 0, date_removed = ? 
-             WHERE uuid = ? AND recipient = ? AND active = 1",
-            params![Utc::now(), uuid, recipient],
-        )?;
-
-        Ok(())
-    }
-
-    fn get_shares(conn: &Connection, uuid: &str) -> Result<Vec<ShareInfo>> {
-        let mut stmt = conn.prepare(
-            "SELECT recipient, share_hash, date_shared, date_removed, active 
-             FROM shares WHERE uuid = ?"
-        )?;
-
-        let shares = stmt.query_map([uuid], |row| {
-            Ok(ShareInfo {
-                recipient: row.get(0)?,
-                share_hash: row.get(1)?,
-                date_shared: row.get(2)?,
-                date_removed: row.get(3)?,
-                active: row.get(4)?,
-            })
-        })?;
-
-        shares.collect::<Result<Vec<_>, _>>().map_err(Into::into)
     }
-}
-
-fn main() -> Result<()> {
-    let opt = Opt::from_args();
+    let config = Config::load(&config_path, profile.as_deref())?;
 
-    // Handle `slink init` command separately
-    if let Opt::Init = opt {
-        commands::initialize_config()?;
-        return Ok(());
+    // Schema upgrades are explicit so the database can be backed up first
+    match opt.command {
+        Command::Db(DbCommand::Migrate) => return commands::db_migrate(&config),
+        Command::Db(DbCommand::Status) => return commands::db_status(&config),
+        _ => {}
     }
-
-    // For all other commands, load the configuration
-    let config = Config::load_or_create()?;
+    let mut store = Store::open(config)?;
 
     // Match and execute other commands
-    match opt {
-        Opt::Add { file, name, share } => {
-            let uuid = commands::add_file(&config, &file, name)?;
+    match opt.command {
+        Command::Add { files, recursive, name, share, dedupe, encrypted, share_flags } => {
+            let encryption = match (share_flags.encrypt, encrypted) {
+                (true, _) => Encryption::Encrypt,
+                (false, true) => Encryption::Encrypted,
+                (false, false) => Encryption::None,
+            };
+            if share.is_some() && opt.format == OutputFormat::Json {
+                return Err(anyhow!("--share cannot be used with --format json"));
+            }
+            let added = commands::add_files(&store, &files, recursive, name, dedupe, encryption, opt.format)?;
             if let Some(recipient) = share {
-                commands::share_file(&config, &recipient, &uuid)?;
+                for added in added {
+                    commands::share_file(&store, &recipient, &added.file.uuid, &share_flags, added.key.as_deref())?;
+                }
             }
         },
-        Opt::Share { recipient, file } => {
-            commands::share_file(&config, &recipient, &file)?;
+        Command::Share { recipient, file, share_flags } => {
+            commands::share_file(&store, &recipient, &file, &share_flags, None)?;
         }
-        Opt::Unshare { recipient, file } => {
-            commands::unshare_file(&config, &recipient, &file)?;
+        Command::Request { recipient, name, expires, list, all, close } => {
+            match (recipient, close) {
+                (Some(recipient), _) => {
+                    commands::request_upload(&store, &recipient, name.as_deref(), expires.as_deref())?;
+                }
+                (None, Some(request_hash)) => commands::close_upload_request(&store, &request_hash)?,
+                (None, None) if list => commands::list_upload_requests(&store, all, opt.format)?,
+                (None, None) => unreachable!("structopt requires a recipient, --list or --close"),
+            }
+        }
+        Command::Unshare { recipient, file } => {
+            commands::unshare_file(&store, &recipient, &file)?;
+        }
+        Command::Show { file } => {
+            commands::show_file(&store, &file, opt.format)?;
+        }
+        Command::List {
+            all, recipient, since, until, active_only, no_shares, name, sort, reverse, limit, offset
+        } => {
+            let filter = ListFilter {
+                all,
+                recipient,
+                since: since.as_deref().map(parse_past_time).transpose()?,
+                until: until.as_deref().map(parse_past_time).transpose()?,
+                active_only,
+                no_shares,
+                name,
+                sort,
+                reverse,
+                limit,
+                offset,
+            };
+            commands::list_files(&store, &filter, opt.format)?;
+        }
+        Command::Chown { owner, file } => {
+            commands::change_owner(&store, &owner, &file)?;
+        }
+        Command::Remove { file, force } => {
+            commands::remove_file(&store, &file, force)?;
+        }
+        Command::Info => {
+            commands::show_info(&store, &config_path, opt.format)?;
+        }
+        Command::Expire => {
+            commands::expire_shares(&store)?;
+        }
+        Command::Serve { listen, cert, key, threads, max_upload } => {
+            serve::serve(store.config().clone(), &listen, cert.zip(key), threads,
+                max_upload.saturating_mul(1024 * 1024))?;
+        }
+        Command::Api { listen, cert, key, threads, max_upload } => {
+            api::serve(store.config().clone(), &listen, cert.zip(key), threads,
+                max_upload.saturating_mul(1024 * 1024))?;
         }
-        Opt::Show { file } => {
-            commands::show_file(&config, &file)?;
+        Command::Token(TokenCommand::Create { name, user }) => {
+            commands::token_create(&store, &name, user.as_deref())?;
         }
-        Opt::List => {
-            commands::list_files(&config)?;
+        Command::Token(TokenCommand::List) => {
+            commands::token_list(&store, opt.format)?;
         }
-        Opt::Remove { file, force } => {
-            commands::remove_file(&config, &file, force)?;
+        Command::Token(TokenCommand::Remove { name }) => {
+            commands::token_remove(&store, &name)?;
         }
-        Opt::Info => {
-            commands::show_info(&config)?;
+        Command::IngestLog { path } => {
+            commands::ingest_log(&store, &path)?;
         }
-        Opt::Init => {
-            // This case is already handled above
+        Command::Verify { file } => {
+            commands::verify_files(&store, file.as_deref())?;
+        }
+        Command::Fsck { repair } => {
+            commands::fsck(&store, repair)?;
+        }
+        Command::RotateSecret { grace, force } => {
+            commands::rotate_secret(&mut store, &config_path, grace.as_deref(), force)?;
+        }
+        Command::Group(GroupCommand::Add { group, recipients }) => {
+            commands::group_add(&store, &group, &recipients)?;
+        }
+        Command::Group(GroupCommand::Remove { group, recipients }) => {
+            commands::group_remove(&store, &group, &recipients)?;
+        }
+        Command::Group(GroupCommand::List { group }) => {
+            commands::group_list(&store, group.as_deref(), opt.format)?;
+        }
+        Command::Audit { command: Some(AuditCommand::Verify), .. } => {
+            commands::audit_verify(&store, opt.format)?;
+        }
+        Command::Audit { command: None, file, hash } => {
+            commands::audit_list(&store, file.as_deref(), hash.as_deref(), opt.format)?;
+        }
+        Command::Init { .. } | Command::Fetch { .. } | Command::Db(_) => {
+            // These cases are already handled above
         }
     }
 
     Ok(())
 }
-
@@ -0,0 +1,418 @@
+// src/migrations.rs
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+use std::collections::BTreeSet;
+use crate::Storage;
+
+/// A numbered schema change. The schema version is kept in `PRAGMA user_version`,
+/// so migration N brings a database from version N-1 to N. Never edit a released
+/// migration; append a new one instead.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create files and shares tables",
+        // Unversioned databases from before migrations already have these tables
+        sql: "
+            CREATE TABLE IF NOT EXISTS files (
+                uuid CHAR(36) NOT NULL PRIMARY KEY,
+                filename TEXT NOT NULL,
+                date_added DATETIME NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS shares (
+                uuid CHAR(36) NOT NULL,
+                recipient TEXT NOT NULL,
+                share_hash TEXT NOT NULL,
+                date_shared DATETIME NOT NULL,
+                date_removed DATETIME,
+                active BOOLEAN NOT NULL DEFAULT 1,
+                PRIMARY KEY (uuid, recipient),
+                FOREIGN KEY (uuid) REFERENCES files(uuid)
+            );
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "Add share expiry",
+        sql: "ALTER TABLE shares ADD COLUMN date_expires DATETIME;",
+    },
+    Migration {
+        version: 3,
+        description: "Add download tracking and download limits",
+        sql: "
+            CREATE TABLE IF NOT EXISTS downloads (
+                uuid CHAR(36) NOT NULL,
+                recipient TEXT NOT NULL,
+                share_hash TEXT NOT NULL,
+                date_accessed DATETIME NOT NULL,
+                ip TEXT NOT NULL,
+                user_agent TEXT NOT NULL,
+                status INTEGER NOT NULL,
+                bytes INTEGER NOT NULL,
+                log_entry TEXT UNIQUE
+            );
+            ALTER TABLE shares ADD COLUMN max_downloads INTEGER;
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "Store file checksum, size and MIME type",
+        sql: "
+            ALTER TABLE files ADD COLUMN checksum TEXT;
+            ALTER TABLE files ADD COLUMN size INTEGER;
+            ALTER TABLE files ADD COLUMN mime_type TEXT;
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "Keep old share links alive after a secret rotation",
+        sql: "
+            CREATE TABLE IF NOT EXISTS retired_links (
+                share_hash TEXT NOT NULL PRIMARY KEY,
+                uuid CHAR(36) NOT NULL,
+                recipient TEXT NOT NULL,
+                date_retired DATETIME NOT NULL,
+                date_expires DATETIME NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "Add password-protected shares",
+        sql: "ALTER TABLE shares ADD COLUMN password_hash TEXT;",
+    },
+    Migration {
+        version: 7,
+        description: "Add recipient groups",
+        sql: "
+            CREATE TABLE recipients (
+                group_name TEXT NOT NULL,
+                recipient TEXT NOT NULL,
+                date_added DATETIME NOT NULL,
+                PRIMARY KEY (group_name, recipient)
+            );
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "Add end-to-end encrypted shares",
+        sql: "ALTER TABLE shares ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT 0;",
+    },
+    Migration {
+        version: 9,
+        description: "Add hash-chained audit log",
+        sql: "
+            CREATE TABLE audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp DATETIME NOT NULL,
+                user TEXT NOT NULL,
+                ssh_client TEXT,
+                command TEXT NOT NULL,
+                action TEXT NOT NULL,
+                uuid CHAR(36),
+                filename TEXT,
+                recipient TEXT,
+                share_hash TEXT,
+                details TEXT,
+                prev_hash TEXT NOT NULL UNIQUE,
+                hash TEXT NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 10,
+        description: "Record the owning user of files and shares",
+        // Existing rows stay without an owner and can be managed by anyone
+        sql: "
+            ALTER TABLE files ADD COLUMN owner TEXT;
+            ALTER TABLE shares ADD COLUMN owner TEXT;
+        ",
+    },
+    Migration {
+        version: 11,
+        description: "Add API tokens",
+        sql: "
+            CREATE TABLE api_tokens (
+                name TEXT NOT NULL PRIMARY KEY,
+                token_hash TEXT NOT NULL UNIQUE,
+                user TEXT NOT NULL,
+                date_created DATETIME NOT NULL,
+                date_last_used DATETIME
+            );
+        ",
+    },
+    Migration {
+        version: 12,
+        description: "Add upload requests",
+        sql: "
+            CREATE TABLE upload_requests (
+                request_hash TEXT NOT NULL PRIMARY KEY,
+                recipient TEXT NOT NULL,
+                name_hint TEXT,
+                owner TEXT NOT NULL,
+                date_created DATETIME NOT NULL,
+                date_expires DATETIME,
+                active BOOLEAN NOT NULL DEFAULT 1
+            );
+            ALTER TABLE files ADD COLUMN request_hash TEXT;
+        ",
+    },
+    Migration {
+        version: 13,
+        description: "Record the owning user of recipient groups",
+        // Existing groups stay without an owner and can be managed by anyone
+        sql: "ALTER TABLE recipients ADD COLUMN owner TEXT;",
+    },
+    Migration {
+        version: 14,
+        description: "Store encrypted files as ciphertext",
+        // Encrypted shares used to publish a per-share copy of a plaintext file;
+        // they are ended here and `slink db migrate` then removes the copies,
+        // see `remove_encrypted_copies`
+        sql: "
+            ALTER TABLE files ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT 0;
+            UPDATE shares SET active = 0, date_removed = CURRENT_TIMESTAMP
+            WHERE encrypted = 1 AND active = 1;
+        ",
+    },
+    Migration {
+        version: 15,
+        description: "Record HTTP client addresses in the audit log",
+        // Earlier API requests and uploads stored theirs in ssh_client; those
+        // entries are left as they are, since changing them breaks the chain
+        sql: "ALTER TABLE audit ADD COLUMN remote_addr TEXT;",
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(Into::into)
+}
+
+/// Applies every pending migration, each in its own transaction together with
+/// the version bump. Returns the migrations that were applied.
+///
+/// Databases created before versioning may already contain some of the tables
+/// and columns of migrations 2 to 5 while still at version 0, so columns that
+/// already exist are skipped and those tables are created only if missing.
+pub fn migrate(conn: &mut Connection) -> Result<Vec<&'static Migration>> {
+    let current = current_version(conn)?;
+    if current > latest_version() {
+        return Err(anyhow!(
+            "Database schema version {} is newer than this slink supports ({})",
+            current, latest_version()
+        ));
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        apply(&tx, migration.sql)
+            .map_err(|e| anyhow!("Migration {} ({}) failed: {}", migration.version, migration.description, e))?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        applied.push(migration);
+    }
+
+    Ok(applied)
+}
+
+/// Hashes, current and retired, of encrypted shares of plaintext files. Before
+/// migration 14 each of them published its own encrypted copy of the file.
+pub(crate) fn encrypted_copy_hashes(conn: &Connection) -> Result<BTreeSet<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT s.share_hash FROM shares s JOIN files f ON f.uuid = s.uuid
+         WHERE s.encrypted = 1 AND f.encrypted = 0
+         UNION
+         SELECT r.share_hash FROM retired_links r
+         JOIN shares s ON s.uuid = r.uuid AND s.recipient = r.recipient
+         JOIN files f ON f.uuid = s.uuid
+         WHERE s.encrypted = 1 AND f.encrypted = 0"
+    )?;
+    let hashes = stmt.query_map([], |row| row.get(0))?;
+    hashes.collect::<Result<BTreeSet<_>, _>>().map_err(Into::into)
+}
+
+/// Unpublishes the copies of the shares migration 14 ended, which SQL alone
+/// cannot reach. Returns how many share hashes were cleaned up.
+pub fn remove_encrypted_copies(conn: &Connection, storage: &dyn Storage) -> Result<usize> {
+    let hashes = encrypted_copy_hashes(conn)?;
+    for share_hash in &hashes {
+        storage.unpublish(share_hash)?;
+    }
+    Ok(hashes.len())
+}
+
+fn apply(conn: &Connection, sql: &str) -> rusqlite::Result<()> {
+    for statement in sql.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+        if let Some((table, column)) = added_column(statement) {
+            let exists = conn.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+                [table, column],
+                |row| row.get::<_, i64>(0),
+            )? > 0;
+            if exists {
+                continue;
+            }
+        }
+        conn.execute_batch(statement)?;
+    }
+    Ok(())
+}
+
+/// The table and column of an `ALTER TABLE t ADD COLUMN c ...` statement.
+fn added_column(statement: &str) -> Option<(&str, &str)> {
+    let words: Vec<&str> = statement.split_whitespace().collect();
+    match words.as_slice() {
+        [alter, table_kw, table, add, column_kw, column, ..]
+            if alter.eq_ignore_ascii_case("ALTER") && table_kw.eq_ignore_ascii_case("TABLE")
+                && add.eq_ignore_ascii_case("ADD") && column_kw.eq_ignore_ascii_case("COLUMN") =>
+        {
+            Some((table, column))
+        }
+        _ => None,
+    }
+}
+
+/// Fails unless the database is at exactly the schema version this binary expects.
+pub fn ensure_current(conn: &Connection) -> Result<()> {
+    let current = current_version(conn)?;
+    let latest = latest_version();
+
+    if current < latest {
+        return Err(anyhow!(
+            "Database schema is at version {} but slink requires version {}. \
+             Back up the database and run `slink db migrate`.",
+            current, latest
+        ));
+    }
+    if current > latest {
+        return Err(anyhow!(
+            "Database schema version {} is newer than this slink supports ({})",
+            current, latest
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The schema of the first release, before databases were versioned.
+    const ORIGINAL_SCHEMA: &str = "
+        CREATE TABLE files (
+            uuid CHAR(36) NOT NULL PRIMARY KEY,
+            filename TEXT NOT NULL,
+            date_added DATETIME NOT NULL
+        );
+        CREATE TABLE shares (
+            uuid CHAR(36) NOT NULL,
+            recipient TEXT NOT NULL,
+            share_hash TEXT NOT NULL,
+            date_shared DATETIME NOT NULL,
+            date_removed DATETIME,
+            active BOOLEAN NOT NULL DEFAULT 1,
+            PRIMARY KEY (uuid, recipient),
+            FOREIGN KEY (uuid) REFERENCES files(uuid)
+        );
+        INSERT INTO files VALUES ('7d3f2c1e-0000-4000-8000-000000000001', 'report.pdf', '2024-01-01 12:00:00');
+        INSERT INTO shares (uuid, recipient, share_hash, date_shared)
+        VALUES ('7d3f2c1e-0000-4000-8000-000000000001', 'bob@example.com', 'abcdefghij', '2024-01-02 12:00:00');
+    ";
+
+    fn original_database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(ORIGINAL_SCHEMA).unwrap();
+        conn
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn.prepare("SELECT name FROM pragma_table_info(?1)").unwrap();
+        let names = stmt.query_map([table], |row| row.get(0)).unwrap();
+        names.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn upgrades_the_original_schema_to_the_latest_version() {
+        let mut conn = original_database();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+
+        let applied = migrate(&mut conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert_eq!(latest_version(), 15);
+        ensure_current(&conn).unwrap();
+
+        // Existing rows survive and pick up the defaults of later columns
+        let (filename, encrypted): (String, bool) = conn.query_row(
+            "SELECT filename, encrypted FROM files", [], |row| Ok((row.get(0)?, row.get(1)?)),
+        ).unwrap();
+        assert_eq!((filename.as_str(), encrypted), ("report.pdf", false));
+        let active: bool = conn.query_row("SELECT active FROM shares", [], |row| row.get(0)).unwrap();
+        assert!(active);
+        assert!(columns(&conn, "audit").contains(&"remote_addr".to_string()));
+    }
+
+    #[test]
+    fn matches_a_new_database() {
+        let mut upgraded = original_database();
+        migrate(&mut upgraded).unwrap();
+        let mut new = Connection::open_in_memory().unwrap();
+        migrate(&mut new).unwrap();
+
+        for table in ["files", "shares", "downloads", "retired_links", "audit"] {
+            assert_eq!(columns(&upgraded, table), columns(&new, table), "table {}", table);
+        }
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let mut conn = original_database();
+        migrate(&mut conn).unwrap();
+
+        assert!(migrate(&mut conn).unwrap().is_empty());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn skips_columns_added_before_versioning() {
+        // Unversioned builds added some of the columns of migrations 2 to 5
+        let mut conn = original_database();
+        conn.execute_batch("
+            ALTER TABLE shares ADD COLUMN date_expires DATETIME;
+            ALTER TABLE files ADD COLUMN checksum TEXT;
+        ").unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        let shares = columns(&conn, "shares");
+        assert_eq!(shares.iter().filter(|name| *name == "date_expires").count(), 1);
+    }
+
+    #[test]
+    fn recognizes_added_columns() {
+        assert_eq!(added_column("ALTER TABLE files ADD COLUMN size INTEGER"), Some(("files", "size")));
+        assert_eq!(added_column("alter  table shares\n add column owner TEXT"), Some(("shares", "owner")));
+        assert_eq!(added_column("ALTER TABLE files RENAME TO old_files"), None);
+        assert_eq!(added_column("CREATE TABLE t (c TEXT)"), None);
+    }
+
+    #[test]
+    fn refuses_a_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1).unwrap();
+        assert!(migrate(&mut conn).is_err());
+        assert!(ensure_current(&conn).is_err());
+    }
+}
@@ -0,0 +1,361 @@
+// src/remote.rs
+use anyhow::{anyhow, Result};
+use slink::RemoteConfig;
+use std::fs::File;
+use slink::crypto;
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use serde::Deserialize;
+use crate::commands::{expand_add_paths, format_size};
+use crate::{OutputFormat, ShareFlags};
+
+/*
+Remote mode:
+- With a [remote] section in the configuration, every command except init and
+  fetch is run as `ssh HOST slink ARGS`, with the terminal, stdin and the exit
+  status passed through; --config and --profile only apply locally
+- `add` streams each local file, or stdin, to `slink --format json add - -n
+  NAME` on the server, hashing it on the way, and compares the BLAKE3 in the
+  server's JSON with the local one before sharing it
+- `add --encrypt` encrypts each file (or stdin) locally and uploads only the
+  ciphertext with the hidden `--encrypted` flag; the key never leaves this
+  machine and is appended to the links the server prints
+*/
+
+/// Runs the command line of this process on the server and exits with its
+/// status if it fails.
+pub fn forward(remote: &RemoteConfig) -> Result<()> {
+    let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+    let status = ssh(remote, &local_args(), interactive).status()
+        .map_err(|e| anyhow!("Failed to run ssh: {}", e))?;
+    exit_on_failure(status)
+}
+
+/// `slink add` for local files: uploads each file, then shares them with
+/// `share` like `add -s` does.
+#[allow(clippy::too_many_arguments)]
+pub fn add_files(
+    remote: &RemoteConfig,
+    paths: &[String],
+    recursive: bool,
+    name: Option<&str>,
+    dedupe: bool,
+    share: Option<&str>,
+    flags: &ShareFlags,
+    format: OutputFormat,
+) -> Result<()> {
+    let files = expand_add_paths(paths, recursive)?;
+    if files.len() > 1 && name.is_some() {
+        return Err(anyhow!("--name can only be used when adding a single file"));
+    }
+    if share.is_some() && format == OutputFormat::Json {
+        return Err(anyhow!("--share cannot be used with --format json"));
+    }
+    if flags.encrypt && flags.notify && share.is_some() {
+        return Err(anyhow!("--notify cannot be used with --encrypt in remote mode; \
+            the server never sees the key the link needs"));
+    }
+    let table = format != OutputFormat::Json;
+
+    let mut added = Vec::new();
+    for file in &files {
+        if files.len() > 1 && table {
+            println!("{}:", file.path);
+        }
+        let name = name.or(file.name.as_deref());
+        added.push(if flags.encrypt {
+            upload_encrypted(remote, &file.path, name, table)?
+        } else {
+            upload(remote, &file.path, name, dedupe, table)?
+        });
+    }
+    if !table {
+        let added: Vec<&serde_json::Value> = added.iter().map(|(_, _, json)| json).collect();
+        println!("{}", serde_json::to_string_pretty(&added)?);
+    }
+    let uuids: Vec<(String, Option<String>)> = added.into_iter().map(|(uuid, key, _)| (uuid, key)).collect();
+
+    if let Some(recipient) = share {
+        let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+        for (uuid, key) in &uuids {
+            let mut command = ssh(remote, &share_args(recipient, uuid, flags), interactive && key.is_none());
+            let key = match key {
+                Some(key) => key,
+                None => {
+                    let status = command.status().map_err(|e| anyhow!("Failed to run ssh: {}", e))?;
+                    exit_on_failure(status)?;
+                    continue;
+                }
+            };
+
+            // The server prints links without the key, which only exists here
+            let output = command.stderr(Stdio::inherit()).output()
+                .map_err(|e| anyhow!("Failed to run ssh: {}", e))?;
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                if line.starts_with("http://") || line.starts_with("https://") {
+                    println!("{}#{}", line, key);
+                } else {
+                    println!("{}", line);
+                }
+            }
+            exit_on_failure(output.status)?;
+        }
+    }
+    Ok(())
+}
+
+/// What the server reported for an upload: UUID, key of an encrypted file and
+/// the entry of `slink add --format json`.
+type Uploaded = (String, Option<String>, serde_json::Value);
+
+/// Encrypts `path`, or stdin for `-`, with a new key into a local temporary
+/// file and uploads that with `upload_with`.
+fn upload_encrypted(remote: &RemoteConfig, path: &str, name: Option<&str>, print: bool) -> Result<Uploaded> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => Path::new(path).file_name()
+            .ok_or_else(|| anyhow!("Invalid file name {}", path))?
+            .to_string_lossy()
+            .to_string(),
+    };
+    let input: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(path).map_err(|e| anyhow!("Failed to open {}: {}", path, e))?)
+    };
+
+    let key = crypto::generate_key();
+    let ciphertext = tempfile::NamedTempFile::new()?;
+    crypto::encrypt(&key, input, BufWriter::new(ciphertext.as_file()))?;
+    let mut ciphertext = File::open(ciphertext.path())?;
+    let size = ciphertext.metadata()?.len();
+    let (uuid, mut json) = upload_with(remote, &mut ciphertext, Some(size), &name, &["--encrypted"], print)?;
+
+    if print {
+        println!("Key: {} (append to links as #{}; it is not stored and cannot be recovered)", key, key);
+    }
+    json["key"] = key.clone().into();
+    Ok((uuid, Some(key), json))
+}
+
+/// Streams `path`, or stdin for `-`, to `slink add -` on the server.
+fn upload(remote: &RemoteConfig, path: &str, name: Option<&str>, dedupe: bool, print: bool) -> Result<Uploaded> {
+    let extra_args: &[&str] = if dedupe { &["--dedupe"] } else { &[] };
+    let (uuid, json) = if path == "-" {
+        // Named like `slink add -` names it on the server
+        let name = name.unwrap_or(path);
+        upload_with(remote, &mut io::stdin().lock(), None, name, extra_args, print)?
+    } else {
+        let path = Path::new(path);
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => path.file_name()
+                .ok_or_else(|| anyhow!("Invalid file name {}", path.display()))?
+                .to_string_lossy()
+                .to_string(),
+        };
+        let mut file = File::open(path)
+            .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+        let size = file.metadata()?.len();
+        upload_with(remote, &mut file, Some(size), &name, extra_args, print)?
+    };
+    Ok((uuid, None, json))
+}
+
+/// The parts of `slink add --format json` output that `upload_with` checks.
+#[derive(Deserialize)]
+struct ServerAdded {
+    file: ServerFile,
+    duplicate: bool,
+}
+
+#[derive(Deserialize)]
+struct ServerFile {
+    uuid: String,
+    filename: String,
+    checksum: Option<String>,
+}
+
+/// Streams `input` (`size` bytes, if known) to `slink add -` with extra
+/// arguments and returns the new UUID and the server's JSON for it once the
+/// server's checksum matches the local one. With `print`, reports the result
+/// like `slink add` does.
+fn upload_with(
+    remote: &RemoteConfig,
+    input: &mut dyn Read,
+    size: Option<u64>,
+    name: &str,
+    extra_args: &[&str],
+    print: bool,
+) -> Result<(String, serde_json::Value)> {
+    let mut args = ["--format", "json", "add", "-", "-n", name].map(str::to_string).to_vec();
+    args.extend(extra_args.iter().map(|arg| arg.to_string()));
+    let mut child = ssh(remote, &args, false)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run ssh: {}", e))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut hasher = blake3::Hasher::new();
+    let mut progress = Progress::new(name, size);
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        // The server only stops reading early on an error, which it reports
+        // itself and which shows in its exit status
+        if stdin.write_all(&buffer[..n]).is_err() {
+            break;
+        }
+        progress.advance(n as u64);
+    }
+    drop(stdin);
+    progress.finish();
+
+    let output = child.wait_with_output()?;
+    exit_on_failure(output.status)?;
+
+    let unexpected = |e| anyhow!("Unexpected answer from {} for {}: {}", remote.host, name, e);
+    let json = serde_json::from_slice::<Vec<serde_json::Value>>(&output.stdout)
+        .map_err(unexpected)?
+        .pop()
+        .ok_or_else(|| anyhow!("{} did not report the file added for {}", remote.host, name))?;
+    let added: ServerAdded = serde_json::from_value(json.clone()).map_err(unexpected)?;
+    let (file, server_checksum) = (&added.file, added.file.checksum.as_deref().unwrap_or_default());
+
+    let checksum = hasher.finalize().to_hex();
+    if server_checksum != checksum.as_str() {
+        return Err(anyhow!("Checksum mismatch for {}: local {}, server {}; the upload was damaged, \
+            remove it with `slink rm {}`", name, checksum, server_checksum, file.uuid));
+    }
+
+    if print {
+        println!("BLAKE3: {}", checksum);
+        match (added.duplicate, extra_args.contains(&"--encrypted")) {
+            (true, _) => println!("Identical file already stored as {} with UUID: {}", file.filename, file.uuid),
+            (false, true) => println!("Added encrypted file with UUID: {}", file.uuid),
+            (false, false) => println!("Added file with UUID: {}", file.uuid),
+        }
+    }
+    Ok((added.file.uuid, json))
+}
+
+/// `slink share` arguments equivalent to `flags`.
+fn share_args(recipient: &str, uuid: &str, flags: &ShareFlags) -> Vec<String> {
+    let mut args = vec!["share".to_string()];
+    if let Some(expires) = &flags.expires {
+        args.extend(["--expires".to_string(), expires.clone()]);
+    }
+    if let Some(max_downloads) = flags.max_downloads {
+        args.extend(["--max-downloads".to_string(), max_downloads.to_string()]);
+    }
+    for (set, flag) in [
+        (flags.password, "--password"),
+        (flags.generate_password, "--generate-password"),
+        (flags.notify, "--notify"),
+        (flags.encrypt, "--encrypt"),
+    ] {
+        if set {
+            args.push(flag.to_string());
+        }
+    }
+    args.extend([recipient.to_string(), uuid.to_string()]);
+    args
+}
+
+/// `ssh HOST slink ARGS`, with a terminal for the remote side if `tty`.
+fn ssh(remote: &RemoteConfig, args: &[String], tty: bool) -> Command {
+    let mut command = Command::new("ssh");
+    command.args(&remote.ssh_options);
+    if tty {
+        command.arg("-t");
+    }
+
+    // ssh passes the remote shell a single command line, so every argument is
+    // quoted; the configured command is used as written
+    let mut words = vec![remote.command.clone().unwrap_or_else(|| "slink".to_string())];
+    if let Some(profile) = &remote.profile {
+        words.extend(["--profile".to_string(), shell_quote(profile)]);
+    }
+    words.extend(args.iter().map(|arg| shell_quote(arg)));
+    command.arg(&remote.host).arg(words.join(" "));
+    command
+}
+
+/// The arguments of this process without the options that only apply locally.
+fn local_args() -> Vec<String> {
+    let mut args = Vec::new();
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            args.push(arg);
+            args.extend(iter.by_ref());
+        } else if arg == "--config" || arg == "--profile" {
+            iter.next();
+        } else if !arg.starts_with("--config=") && !arg.starts_with("--profile=") {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Exits with the remote command's status, which has already printed its own
+/// error, unless it succeeded.
+fn exit_on_failure(status: ExitStatus) -> Result<()> {
+    if status.success() {
+        return Ok(());
+    }
+    io::stdout().flush()?;
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+/// Upload progress on stderr, shown only on a terminal.
+struct Progress<'a> {
+    name: &'a str,
+    size: Option<u64>,
+    sent: u64,
+    percent: Option<u64>,
+    visible: bool,
+}
+
+impl<'a> Progress<'a> {
+    fn new(name: &'a str, size: Option<u64>) -> Progress<'a> {
+        Progress { name, size, sent: 0, percent: None, visible: io::stderr().is_terminal() }
+    }
+
+    /// Shows the percentage, or for stdin the amount sent in whole MiB.
+    fn advance(&mut self, bytes: u64) {
+        self.sent += bytes;
+        let percent = match self.size {
+            Some(size) => (self.sent * 100).checked_div(size).unwrap_or(100),
+            None => self.sent >> 20,
+        };
+        if self.visible && self.percent != Some(percent) {
+            self.percent = Some(percent);
+            match self.size {
+                Some(size) => eprint!("\rUploading {}: {}% ({} of {})", self.name, percent,
+                    format_size(self.sent), format_size(size)),
+                None => eprint!("\rUploading {}: {}", self.name, format_size(self.sent)),
+            }
+        }
+    }
+
+    fn finish(&self) {
+        if self.visible && self.percent.is_some() {
+            eprintln!();
+        }
+    }
+}
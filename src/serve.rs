@@ -0,0 +1,541 @@
+// src/serve.rs
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server, SslConfig, StatusCode};
+use crate::{storage, verify_password, Actor, Config, Download, InvalidInput, ShareInfo, Storage, Store};
+use crate::UploadRequest;
+
+/// Characters left unescaped in an RFC 5987 `filename*` parameter
+const FILENAME_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A share resolved from a request path.
+struct ServedShare {
+    uuid: String,
+    filename: String,
+    share_hash: String,
+    mime_type: Option<String>,
+    password_hash: Option<String>,
+    encrypted: bool,
+    max_downloads: Option<i64>,
+}
+
+/// Outcome of a request: status, body bytes sent and the share served, if any.
+/// `reserved` is the download recorded before a download-limited share was sent.
+struct Outcome {
+    status: u16,
+    bytes: u64,
+    share_hash: Option<String>,
+    reserved: Option<i64>,
+}
+
+pub fn serve(
+    config: Config,
+    listen: &str,
+    tls: Option<(String, String)>,
+    threads: usize,
+    max_upload: u64,
+) -> Result<()> {
+    let server = bind(listen, tls.as_ref())?;
+    let storage: Arc<dyn Storage> = Arc::from(storage::open(&config)?);
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    println!("Serving {} on {}://{}", storage.location(), scheme, listen);
+
+    let server = Arc::new(server);
+    let config = Arc::new(config);
+    let prefix = url_path_prefix(&config.base_url);
+
+    let workers = (0..threads.max(1)).map(|_| {
+        let server = Arc::clone(&server);
+        let config = Arc::clone(&config);
+        let storage = Arc::clone(&storage);
+        let prefix = prefix.clone();
+        thread::spawn(move || -> Result<()> {
+            // Each worker keeps its own connection; rusqlite connections are not Sync
+            let conn = Connection::open(&config.db_path)?;
+            conn.busy_timeout(Duration::from_secs(5))?;
+            for request in server.incoming_requests() {
+                handle_request(&conn, &config, storage.as_ref(), &prefix, max_upload, request);
+            }
+            Ok(())
+        })
+    }).collect::<Vec<_>>();
+
+    for worker in workers {
+        worker.join().map_err(|_| anyhow!("Server worker panicked"))??;
+    }
+    Ok(())
+}
+
+/// Listens on `listen`, with TLS if a certificate and key file are given.
+pub(crate) fn bind(listen: &str, tls: Option<&(String, String)>) -> Result<Server> {
+    match tls {
+        Some((cert, key)) => {
+            let ssl = SslConfig {
+                certificate: fs::read(cert)
+                    .map_err(|e| anyhow!("Failed to read certificate {}: {}", cert, e))?,
+                private_key: fs::read(key)
+                    .map_err(|e| anyhow!("Failed to read private key {}: {}", key, e))?,
+            };
+            Server::https(listen, ssl)
+        }
+        None => Server::http(listen),
+    }.map_err(|e| anyhow!("Failed to listen on {}: {}", listen, e))
+}
+
+/// Returns the path component of `base_url` without a trailing slash, so that
+/// `https://example.com/f` serves shares under `/f/HASH/filename`.
+fn url_path_prefix(base_url: &str) -> String {
+    let without_scheme = base_url.split_once("://").map_or(base_url, |(_, rest)| rest);
+    without_scheme.find('/')
+        .map_or("", |i| &without_scheme[i..])
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn handle_request(
+    conn: &Connection,
+    config: &Config,
+    storage: &dyn Storage,
+    prefix: &str,
+    max_upload: u64,
+    request: Request,
+) {
+    let remote = request.remote_addr().map_or("-".to_string(), |a| a.ip().to_string());
+    let request_line = format!("{} {} HTTP/{}", request.method(), request.url(), request.http_version());
+    let user_agent = header_value(&request, "User-Agent").unwrap_or_else(|| "-".to_string());
+
+    let outcome = match respond(conn, config, storage, prefix, max_upload, request) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("Error serving {}: {}", request_line, e);
+            Outcome { status: 500, bytes: 0, share_hash: None, reserved: None }
+        }
+    };
+    let now = Utc::now();
+
+    // Combined log format. Downloads are recorded directly below, so this log
+    // must not be passed to `slink ingest-log` as well
+    println!(
+        "{} - - [{}] \"{}\" {} {} \"-\" \"{}\"",
+        remote,
+        now.format("%d/%b/%Y:%H:%M:%S %z"),
+        request_line,
+        outcome.status,
+        outcome.bytes,
+        user_agent
+    );
+
+    if let Some(share_hash) = outcome.share_hash {
+        let download = Download {
+            share_hash,
+            date_accessed: now,
+            ip: remote,
+            user_agent,
+            status: outcome.status,
+            bytes: outcome.bytes,
+            log_entry: None,
+        };
+        if let Err(e) = account_download(conn, storage, &download, outcome.reserved) {
+            eprintln!("Error recording download for {}: {}", download.share_hash, e);
+        }
+    }
+}
+
+fn account_download(
+    conn: &Connection,
+    storage: &dyn Storage,
+    download: &Download,
+    reserved: Option<i64>,
+) -> Result<()> {
+    match reserved {
+        Some(id) => Download::complete(conn, id, download.bytes)?,
+        None => {
+            download.record(conn)?;
+        }
+    }
+    let revoked = ShareInfo::enforce_download_limit(conn, storage, &Actor::current(), &download.share_hash)?;
+    if let Some((_, recipient)) = revoked {
+        eprintln!("Download limit reached, removed share {} for {}", download.share_hash, recipient);
+    }
+    Ok(())
+}
+
+pub(crate) fn header_value(request: &Request, name: &'static str) -> Option<String> {
+    request.headers().iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+/// Answers a single request. Only GET requests for a share report the share,
+/// so HEAD requests and errors are not counted as downloads. A download of a
+/// download-limited share is reserved before it is sent and refused with 404
+/// once the limit is used up. Such shares ignore `Range`, since partial
+/// downloads are not counted and could otherwise fetch the file piecewise.
+fn respond(
+    conn: &Connection,
+    config: &Config,
+    storage: &dyn Storage,
+    prefix: &str,
+    max_upload: u64,
+    request: Request,
+) -> Result<Outcome> {
+    if let Some((request_hash, filename)) = upload_path(prefix, request.url()) {
+        return respond_upload(config, &request_hash, filename, max_upload, request);
+    }
+
+    let is_head = match request.method() {
+        Method::Get => false,
+        Method::Head => true,
+        _ => return send_status(request, 405, "Method Not Allowed"),
+    };
+
+    let share = match resolve_request_path(conn, prefix, request.url())? {
+        Some(share) => share,
+        None => return send_status(request, 404, "Not Found"),
+    };
+
+    if let Some(password_hash) = &share.password_hash {
+        let authorized = header_value(&request, "Authorization")
+            .and_then(|value| basic_auth_password(&value))
+            .is_some_and(|password| verify_password(&password, password_hash));
+        if !authorized {
+            let headers = vec![header("WWW-Authenticate", "Basic realm=\"slink\", charset=\"UTF-8\"")];
+            return send_with_headers(request, 401, headers);
+        }
+    }
+
+    let size = match storage.size(&share.uuid, &share.filename)? {
+        Some(size) => size,
+        None => return send_status(request, 404, "Not Found"),
+    };
+
+    let limited = share.max_downloads.is_some();
+    let mut headers = vec![
+        header("Accept-Ranges", if limited { "none" } else { "bytes" }),
+        header("Content-Type", match (&share.mime_type, share.encrypted) {
+            (Some(mime_type), false) => mime_type,
+            _ => "application/octet-stream",
+        }),
+        header("Content-Disposition", &content_disposition(&share.filename)),
+    ];
+
+    let range = header_value(&request, "Range")
+        .filter(|_| !limited)
+        .and_then(|value| parse_range(&value, size));
+    let (status, start, length) = match range {
+        Some(Ok((start, end))) => {
+            headers.push(header("Content-Range", &format!("bytes {}-{}/{}", start, end, size)));
+            (206, start, end - start + 1)
+        }
+        Some(Err(())) => {
+            headers.push(header("Content-Range", &format!("bytes */{}", size)));
+            return send_with_headers(request, 416, headers);
+        }
+        None => (200, 0, size),
+    };
+
+    let reserved = if limited && !is_head {
+        let download = Download {
+            share_hash: share.share_hash.clone(),
+            date_accessed: Utc::now(),
+            ip: request.remote_addr().map_or("-".to_string(), |a| a.ip().to_string()),
+            user_agent: header_value(&request, "User-Agent").unwrap_or_else(|| "-".to_string()),
+            status,
+            bytes: 0,
+            log_entry: None,
+        };
+        match download.reserve(conn)? {
+            Some(id) => Some(id),
+            None => return send_status(request, 404, "Not Found"),
+        }
+    } else {
+        None
+    };
+
+    let sent = Arc::new(AtomicU64::new(0));
+    let body: Box<dyn Read + Send> = if is_head || length == 0 {
+        Box::new(std::io::empty())
+    } else {
+        let range = (start, start + length - 1);
+        match storage.read(&share.uuid, &share.filename, Some(range)) {
+            Ok(Some(reader)) => Box::new(CountingReader { inner: reader.take(length), count: Arc::clone(&sent) }),
+            result => {
+                if let Some(id) = reserved {
+                    Download::release(conn, id)?;
+                }
+                result?;
+                return send_status(request, 404, "Not Found");
+            }
+        }
+    };
+
+    let response = Response::new(StatusCode(status), headers, body, Some(length as usize), None);
+    let result = request.respond(response);
+
+    // tiny_http stops reading the body when the client goes away without
+    // always reporting it, so a short count means the download was cut off
+    // and must not use up the limit
+    let sent = sent.load(Ordering::Relaxed);
+    if let Some(id) = reserved {
+        if result.is_err() || sent < length {
+            Download::release(conn, id)?;
+        }
+    }
+    result?;
+
+    Ok(if is_head {
+        Outcome { status, bytes: 0, share_hash: None, reserved: None }
+    } else if sent < length {
+        Outcome { status, bytes: sent, share_hash: None, reserved: None }
+    } else {
+        Outcome { status, bytes: length, share_hash: Some(share.share_hash), reserved }
+    })
+}
+
+/// Splits `/upload/<request_hash>[/<filename>]` into its parts.
+fn upload_path(prefix: &str, url: &str) -> Option<(String, Option<String>)> {
+    let path = url.split(['?', '#']).next()?
+        .strip_prefix(prefix)?
+        .strip_prefix("/upload/")?;
+    let (request_hash, filename) = path.split_once('/').unwrap_or((path, ""));
+    let filename = percent_decode_str(filename).decode_utf8().ok()?;
+    Some((request_hash.to_string(), Some(filename.to_string()).filter(|name| !name.is_empty())))
+}
+
+/// Answers requests for an upload link: GET explains how to upload, POST and
+/// PUT store the body through `Store::receive_upload`. Closed, expired and
+/// unknown requests yield 404, bodies larger than `max_upload` bytes 413.
+fn respond_upload(
+    config: &Config,
+    request_hash: &str,
+    filename: Option<String>,
+    max_upload: u64,
+    mut request: Request,
+) -> Result<Outcome> {
+    let mut store = Store::open(config.clone())?;
+    let upload_request = match store.upload_request(request_hash)?.filter(UploadRequest::is_open) {
+        Some(upload_request) => upload_request,
+        None => return send_status(request, 404, "Not Found"),
+    };
+
+    match request.method() {
+        Method::Get | Method::Head => {
+            let instructions = format!(
+                "Upload link for {}. Send a file with:\n\n  curl -T FILE {}\n",
+                upload_request.recipient,
+                store.upload_url(&upload_request)
+            );
+            return send_status(request, 200, &instructions);
+        }
+        Method::Post | Method::Put => {}
+        _ => return send_status(request, 405, "Method Not Allowed"),
+    }
+    let too_large = format!("Upload larger than {} bytes\n", max_upload);
+    if request.body_length().is_some_and(|length| length as u64 > max_upload) {
+        return send_status(request, 413, &too_large);
+    }
+
+    let remote = request.remote_addr().map(|a| a.ip().to_string());
+    store.set_actor(Actor {
+        ssh_client: None,
+        remote_addr: remote,
+        command: format!("serve upload {}", request_hash),
+        ..Actor::current()
+    });
+    let mut body = LimitedReader::new(request.as_reader(), max_upload);
+    let received = store.receive_upload(request_hash, &mut body, filename.as_deref());
+    match received {
+        Ok(file) => {
+            let message = format!("Received {} ({} bytes)\nBLAKE3: {}\n",
+                file.filename, file.size.unwrap_or_default(), file.checksum.unwrap_or_default());
+            send_status(request, 201, &message)
+        }
+        Err(_) if body.exceeded => send_status(request, 413, &too_large),
+        Err(e) if e.chain().any(|cause| cause.is::<InvalidInput>()) => {
+            send_status(request, 400, &format!("Upload failed: {}\n", e))
+        }
+        Err(e) => {
+            eprintln!("Error receiving upload for {}: {:#}", request_hash, e);
+            send_status(request, 500, "Upload failed\n")
+        }
+    }
+}
+
+/// Maps `/<share_hash>/<filename>` to the share it names. Unknown, revoked and
+/// expired shares, as well as a filename not matching the stored one, yield `None`.
+fn resolve_request_path(conn: &Connection, prefix: &str, url: &str) -> Result<Option<ServedShare>> {
+    let path = url.split(['?', '#']).next().unwrap_or("");
+    let path = match path.strip_prefix(prefix) {
+        Some(path) if path.starts_with('/') => path,
+        _ => return Ok(None),
+    };
+
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let (share_hash, filename) = match segments.as_slice() {
+        [hash, name] if !hash.is_empty() && !name.is_empty() => (*hash, *name),
+        _ => return Ok(None),
+    };
+    let filename = match percent_decode_str(filename).decode_utf8() {
+        Ok(filename) => filename,
+        Err(_) => return Ok(None),
+    };
+
+    let share = conn.query_row(
+        "SELECT s.uuid, f.filename, s.share_hash, f.mime_type, s.password_hash, f.encrypted, s.date_expires,
+                s.max_downloads,
+                (SELECT COUNT(*) FROM downloads d
+                 WHERE d.uuid = s.uuid AND d.recipient = s.recipient AND d.status = 200)
+         FROM shares s JOIN files f ON f.uuid = s.uuid
+         WHERE s.active = 1 AND (
+             s.share_hash = ?1
+             OR EXISTS (SELECT 1 FROM retired_links r
+                        WHERE r.share_hash = ?1 AND r.uuid = s.uuid
+                          AND r.recipient = s.recipient AND r.date_expires > ?2)
+         )",
+        params![share_hash, Utc::now()],
+        |row| Ok((
+            ServedShare {
+                uuid: row.get(0)?,
+                filename: row.get(1)?,
+                share_hash: row.get(2)?,
+                mime_type: row.get(3)?,
+                password_hash: row.get(4)?,
+                encrypted: row.get(5)?,
+                max_downloads: row.get(7)?,
+            },
+            row.get::<_, Option<DateTime<Utc>>>(6)?,
+            row.get::<_, i64>(8)?,
+        )),
+    ).optional()?;
+
+    Ok(share.and_then(|(share, expires, downloads)| {
+        let expired = expires.is_some_and(|e| e <= Utc::now());
+        let used_up = share.max_downloads.is_some_and(|max| downloads >= max);
+        (!expired && !used_up && share.filename == filename).then_some(share)
+    }))
+}
+
+/// Parses a single `bytes=` range. Returns `None` when the header should be
+/// ignored (unsupported or multiple ranges) and `Some(Err(()))` when it cannot
+/// be satisfied for a file of `size` bytes.
+fn parse_range(value: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // Suffix range: the last N bytes
+        let suffix = end.parse::<u64>().ok()?;
+        if suffix == 0 || size == 0 {
+            return Some(Err(()));
+        }
+        (size.saturating_sub(suffix), size - 1)
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = if end.is_empty() { size.saturating_sub(1) } else { end.parse::<u64>().ok()? };
+        if start >= size || end < start {
+            return Some(Err(()));
+        }
+        (start, end.min(size - 1))
+    };
+
+    Some(Ok(range))
+}
+
+/// Extracts the password from a `Basic` Authorization header. The user name is
+/// ignored; recipients are identified by the link, not by what they type.
+fn basic_auth_password(value: &str) -> Option<String> {
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    decoded.split_once(':').map(|(_, password)| password.to_string())
+}
+
+pub(crate) fn content_disposition(filename: &str) -> String {
+    // Plain ASCII fallback for old clients plus the RFC 5987 encoded name
+    let fallback: String = filename.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        utf8_percent_encode(filename, FILENAME_ENCODE_SET)
+    )
+}
+
+pub(crate) fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn send_status(request: Request, status: u16, message: &str) -> Result<Outcome> {
+    let response = Response::from_string(message).with_status_code(status);
+    request.respond(response)?;
+    Ok(Outcome { status, bytes: message.len() as u64, share_hash: None, reserved: None })
+}
+
+fn send_with_headers(request: Request, status: u16, headers: Vec<Header>) -> Result<Outcome> {
+    let response = Response::new(StatusCode(status), headers, std::io::empty(), Some(0), None);
+    request.respond(response)?;
+    Ok(Outcome { status, bytes: 0, share_hash: None, reserved: None })
+}
+
+/// Counts the bytes read through it, i.e. handed to the connection.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Passes through at most `limit` bytes and fails once a body is larger,
+/// noting that in `exceeded`.
+pub(crate) struct LimitedReader<R> {
+    inner: R,
+    limit: u64,
+    pub(crate) exceeded: bool,
+}
+
+impl<R> LimitedReader<R> {
+    pub(crate) fn new(inner: R, limit: u64) -> LimitedReader<R> {
+        LimitedReader { inner, limit, exceeded: false }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.limit = match self.limit.checked_sub(n as u64) {
+            Some(limit) => limit,
+            None => {
+                self.exceeded = true;
+                return Err(std::io::Error::other("request body too large"));
+            }
+        };
+        Ok(n)
+    }
+}
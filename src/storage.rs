@@ -0,0 +1,167 @@
+// src/storage.rs
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::symlink as unix_symlink;
+use std::path::{Path, PathBuf};
+use crate::s3::S3Storage;
+use crate::{create_dir_all, remove_file_with_access, remove_share_link, set_permissions_recursive};
+use crate::{Config, StorageConfig};
+
+/// Where file data lives and how shares are published. The database keeps
+/// all share bookkeeping; a backend only stores bytes under `UUID/filename`
+/// and makes them reachable under `HASH/filename` for unprotected shares.
+pub trait Storage: Send + Sync {
+    /// Stores the data of file `uuid` from `source`. If `reuse` names an
+    /// identical stored file, its data may be shared instead of copied;
+    /// returns whether that happened.
+    fn put(
+        &self,
+        uuid: &str,
+        filename: &str,
+        source: &Path,
+        mime_type: &str,
+        reuse: Option<(&str, &str)>,
+    ) -> Result<bool>;
+
+    /// Size of the stored data, or `None` if it is missing.
+    fn size(&self, uuid: &str, filename: &str) -> Result<Option<u64>>;
+
+    /// Reads the stored data, or the inclusive byte range `range` of it.
+    /// Returns `None` if it is missing.
+    fn read(
+        &self,
+        uuid: &str,
+        filename: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<Box<dyn Read + Send>>>;
+
+    /// Deletes the stored data. Callers must first `unpublish` every share of
+    /// it: the local backend also drops stray links to the file, but S3 has no
+    /// way to find the copies published from it.
+    fn remove(&self, uuid: &str, filename: &str) -> Result<()>;
+
+    /// Makes the file reachable at `base_url/HASH/filename`.
+    fn publish(&self, share_hash: &str, uuid: &str, filename: &str) -> Result<()>;
+
+    /// Removes whatever `publish` created for `share_hash`. Missing links are
+    /// not an error.
+    fn unpublish(&self, share_hash: &str) -> Result<()>;
+
+    /// Human-readable location of the store, e.g. `/var/www` or `s3://bucket`.
+    fn location(&self) -> String;
+}
+
+/// Opens the backend selected by the `[storage]` section, BASE_DIR by default.
+pub fn open(config: &Config) -> Result<Box<dyn Storage>> {
+    Ok(match &config.storage {
+        None | Some(StorageConfig::Local) => Box::new(LocalStorage::new(config)),
+        Some(StorageConfig::S3(s3)) => Box::new(S3Storage::new(s3)?),
+    })
+}
+
+/// The original layout: BASE_DIR/UUID/filename served by the web server, with
+/// shares published as BASE_DIR/HASH -> UUID symlinks.
+pub struct LocalStorage {
+    config: Config,
+}
+
+impl LocalStorage {
+    pub fn new(config: &Config) -> LocalStorage {
+        LocalStorage { config: config.clone() }
+    }
+
+    fn path(&self, uuid: &str, filename: &str) -> PathBuf {
+        PathBuf::from(&self.config.base_dir).join(uuid).join(filename)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(
+        &self,
+        uuid: &str,
+        filename: &str,
+        source: &Path,
+        _mime_type: &str,
+        reuse: Option<(&str, &str)>,
+    ) -> Result<bool> {
+        let target_dir = PathBuf::from(&self.config.base_dir).join(uuid);
+        let target_file = target_dir.join(filename);
+
+        create_dir_all(&target_dir)?;
+
+        // Hard links fail across filesystems, in which case the file is copied
+        let reused = reuse.is_some_and(|(uuid, filename)| {
+            fs::hard_link(self.path(uuid, filename), &target_file).is_ok()
+        });
+        if !reused {
+            fs::copy(source, &target_file)?;
+        }
+
+        set_permissions_recursive(
+            &target_dir,
+            0o750,
+            0o640,
+            &self.config.web_user,
+            &self.config.web_group,
+        )?;
+        Ok(reused)
+    }
+
+    fn size(&self, uuid: &str, filename: &str) -> Result<Option<u64>> {
+        Ok(fs::metadata(self.path(uuid, filename)).ok().map(|m| m.len()))
+    }
+
+    fn read(
+        &self,
+        uuid: &str,
+        filename: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<Box<dyn Read + Send>>> {
+        let mut file = match File::open(self.path(uuid, filename)) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(match range {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start))?;
+                Box::new(file.take(end - start + 1))
+            }
+            None => Box::new(file),
+        }))
+    }
+
+    fn remove(&self, uuid: &str, _filename: &str) -> Result<()> {
+        // Remove all symlinks, including any the database no longer knows about
+        let shares_dir = PathBuf::from(&self.config.base_dir);
+        for entry in fs::read_dir(&shares_dir)? {
+            let entry = entry?;
+            if let Ok(target) = fs::read_link(entry.path()) {
+                if target.ends_with(uuid) {
+                    remove_file_with_access(&entry.path())?;
+                }
+            }
+        }
+
+        // Remove the file directory (already gone if fsck is cleaning up after a crash)
+        let file_dir = shares_dir.join(uuid);
+        if file_dir.exists() {
+            remove_file_with_access(&file_dir)?;
+        }
+        Ok(())
+    }
+
+    fn publish(&self, share_hash: &str, uuid: &str, _filename: &str) -> Result<()> {
+        // Create symlink with relative path
+        unix_symlink(uuid, PathBuf::from(&self.config.base_dir).join(share_hash))?;
+        Ok(())
+    }
+
+    fn unpublish(&self, share_hash: &str) -> Result<()> {
+        remove_share_link(&self.config, share_hash)
+    }
+
+    fn location(&self) -> String {
+        self.config.base_dir.clone()
+    }
+}
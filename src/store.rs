@@ -0,0 +1,969 @@
+// src/store.rs
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::{fmt, fs};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+use crate::audit::{self, AuditEntry, ChainVerification, Event};
+use crate::fsck::{self, FsckReport};
+use crate::ingest::{self, IngestReport};
+use crate::{crypto, migrations, storage};
+use crate::{Actor, ApiToken, Config, FileShare, RecipientGroup, RotatedShare, ShareInfo, ShareOptions, Storage};
+use crate::UploadRequest;
+
+/// A slink installation: its configuration, an open database connection and
+/// the storage backend. Methods return typed values and never print, so other
+/// Rust programs can manage files and shares without going through the
+/// command line. Every change is recorded in the audit log under `actor`.
+pub struct Store {
+    config: Config,
+    conn: Connection,
+    storage: Box<dyn Storage>,
+    actor: Actor,
+}
+
+/// Result of `Store::add`.
+pub enum AddOutcome {
+    /// The file was stored under a new UUID. `reused` is the identical file
+    /// whose data was hard linked instead of copied, if any.
+    Added { file: FileShare, reused: Option<FileShare> },
+    /// `dedupe` was requested and identical content is already stored, so
+    /// nothing was added.
+    Duplicate(FileShare),
+}
+
+impl AddOutcome {
+    pub fn file(&self) -> &FileShare {
+        match self {
+            AddOutcome::Added { file, .. } => file,
+            AddOutcome::Duplicate(file) => file,
+        }
+    }
+}
+
+/// A file together with its number of active shares, as listed by `slink ls`.
+#[derive(Serialize)]
+pub struct FileListing {
+    #[serde(flatten)]
+    pub file: FileShare,
+    pub active_shares: i64,
+}
+
+/// Which files `Store::list` returns, and in what order.
+#[derive(Debug, Default)]
+pub struct ListFilter {
+    /// Include files owned by other users
+    pub all: bool,
+    /// Only files shared with this recipient, including past shares unless
+    /// `active_only` is set
+    pub recipient: Option<String>,
+    /// Only files added at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only files added before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Only files with at least one active share
+    pub active_only: bool,
+    /// Only files without active shares
+    pub no_shares: bool,
+    /// Only files whose name matches this glob (`*`, `?`, `[...]`), ignoring case
+    pub name: Option<String>,
+    pub sort: ListSort,
+    /// Reverse the order of `sort`
+    pub reverse: bool,
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+/// Sort order of `slink ls`. Names sort A to Z; dates, sizes and share counts
+/// largest first.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ListSort {
+    #[default]
+    Date,
+    Name,
+    Size,
+    Shares,
+}
+
+impl ListSort {
+    /// The ORDER BY expression and whether it is descending.
+    fn order(self) -> (&'static str, bool) {
+        match self {
+            ListSort::Date => ("f.date_added", true),
+            ListSort::Name => ("f.filename COLLATE NOCASE", false),
+            ListSort::Size => ("f.size", true),
+            ListSort::Shares => ("share_count", true),
+        }
+    }
+}
+
+impl std::str::FromStr for ListSort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "date" => Ok(ListSort::Date),
+            "name" => Ok(ListSort::Name),
+            "size" => Ok(ListSort::Size),
+            "shares" => Ok(ListSort::Shares),
+            _ => Err(invalid!("Unknown sort order: {} (use date, name, size or shares)", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Statistics {
+    pub file_count: i64,
+    pub total_shares: i64,
+    pub active_shares: i64,
+    pub oldest_file: Option<DateTime<Utc>>,
+}
+
+/// Result of checking a stored file against its recorded checksum and size.
+pub enum Verification {
+    Ok,
+    Missing,
+    /// Added before checksums were stored
+    NoChecksum,
+    ChecksumMismatch { actual: String },
+    SizeMismatch { actual: u64 },
+}
+
+impl Store {
+    /// Opens the database and storage backend named by `config`. Fails unless
+    /// the schema is at the version this crate expects; see `migrations::migrate`.
+    pub fn open(config: Config) -> Result<Store> {
+        let storage = storage::open(&config)?;
+        Store::with_storage(config, storage)
+    }
+
+    /// Like `open`, with a storage backend of the caller's choosing.
+    pub fn with_storage(config: Config, storage: Box<dyn Storage>) -> Result<Store> {
+        let conn = Connection::open(&config.db_path)?;
+        migrations::ensure_current(&conn)?;
+        Ok(Store { config, conn, storage, actor: Actor::current() })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Who changes are attributed to in the audit log and checked against file
+    /// ownership; the current process by default.
+    pub fn set_actor(&mut self, actor: Actor) {
+        self.actor = actor;
+    }
+
+    pub fn actor(&self) -> &Actor {
+        &self.actor
+    }
+
+    /// Whether the actor may manage files owned by others, see `Config::is_admin`.
+    pub fn is_admin(&self) -> bool {
+        self.config.is_admin(&self.actor.user)
+    }
+
+    /// Whether the actor owns `file`, is an admin, or the file has no owner.
+    pub fn may_manage(&self, file: &FileShare) -> bool {
+        file.owner.as_ref().is_none_or(|owner| *owner == self.actor.user) || self.is_admin()
+    }
+
+    fn check_owner(&self, file: &FileShare) -> Result<()> {
+        if self.may_manage(file) {
+            return Ok(());
+        }
+        Err(PermissionDenied(format!("{} belongs to {}; only its owner or an admin may change it",
+            file.filename, file.owner.as_deref().unwrap_or_default())).into())
+    }
+
+    fn check_group_owner(&self, group: &RecipientGroup) -> Result<()> {
+        match &group.owner {
+            Some(owner) if *owner != self.actor.user && !self.is_admin() => {
+                Err(PermissionDenied(format!("@{} belongs to {}; only its owner or an admin may change it",
+                    group.name, owner)).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_admin(&self, what: &str) -> Result<()> {
+        if self.is_admin() {
+            return Ok(());
+        }
+        Err(PermissionDenied(format!("Only members of the admin group may {}", what)).into())
+    }
+
+    fn log(&self, event: Event) -> Result<()> {
+        audit::record(&self.conn, &self.actor, event)
+    }
+
+    /// See `audit::with_transaction`.
+    fn logged<T>(&self, change: impl FnOnce() -> Result<T>) -> Result<T> {
+        audit::with_transaction(&self.conn, change)
+    }
+
+    /// An event about the share of `uuid` with `recipient`, filled in from
+    /// the database so that it stays meaningful after the file is removed.
+    fn share_event(&self, action: &'static str, uuid: &str, recipient: &str) -> Result<Event> {
+        let share_hash = self.shares(uuid)?.into_iter()
+            .find(|share| share.recipient == recipient)
+            .map(|share| share.share_hash);
+        Ok(Event {
+            action,
+            uuid: Some(uuid.to_string()),
+            filename: self.file(uuid)?.map(|file| file.filename),
+            recipient: Some(recipient.to_string()),
+            share_hash,
+            details: None,
+        })
+    }
+
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    /// Looks up a file by UUID, by name, or by `name/N` for the N-th (oldest
+    /// first) of several files with the same name. Names are looked up among
+    /// the actor's own files first, then among everyone's.
+    pub fn resolve(&self, file_spec: &str) -> Result<FileShare> {
+        // If input looks like a UUID, use it directly
+        if file_spec.len() == 36 && file_spec.chars().filter(|c| *c == '-').count() == 4 {
+            return self.file(file_spec)?.ok_or_else(|| invalid!("File not found: {}", file_spec));
+        }
+
+        // Split filename and optional index
+        let parts: Vec<&str> = file_spec.split('/').collect();
+        let (filename, index) = match parts.as_slice() {
+            [filename] => (filename, 1),
+            [filename, index_str] => (filename, index_str.parse::<usize>()
+                .map_err(|_| invalid!("Invalid index format"))?),
+            _ => return Err(invalid!("Invalid file specification")),
+        };
+
+        let mut matches = FileShare::find_by_name(&self.conn, filename, Some(&self.actor.user))?;
+        if matches.is_empty() {
+            matches = FileShare::find_by_name(&self.conn, filename, None)?;
+        }
+
+        if matches.is_empty() {
+            return Err(invalid!("File not found: {}", filename));
+        }
+
+        if matches.len() > 1 && parts.len() == 1 {
+            let candidates: Vec<String> = matches.iter().enumerate()
+                .map(|(i, (uuid, date))| format!("{}/{}: {} ({})",
+                    filename, i + 1, uuid, date.format("%Y-%m-%d %H:%M:%S")))
+                .collect();
+            return Err(invalid!("Multiple files found:\n{}\nPlease specify file index",
+                candidates.join("\n")));
+        }
+
+        let uuid = matches.get(index.wrapping_sub(1))
+            .map(|(uuid, _)| uuid.clone())
+            .ok_or_else(|| invalid!("Invalid file index"))?;
+        self.file(&uuid)?.ok_or_else(|| invalid!("File not found: {}", uuid))
+    }
+
+    pub fn file(&self, uuid: &str) -> Result<Option<FileShare>> {
+        FileShare::find_by_uuid(&self.conn, uuid)
+    }
+
+    /// All files, oldest first.
+    pub fn files(&self) -> Result<Vec<FileShare>> {
+        FileShare::find_all(&self.conn)
+    }
+
+    /// The actor's files and those without an owner, or with `all` every file,
+    /// with their active share count, narrowed, sorted and paged as `filter`
+    /// says.
+    pub fn list(&self, filter: &ListFilter) -> Result<Vec<FileListing>> {
+        let (order, descending) = filter.sort.order();
+        let direction = if descending != filter.reverse { "DESC" } else { "ASC" };
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}, COUNT(s.uuid) as share_count
+             FROM files f
+             LEFT JOIN shares s ON f.uuid = s.uuid AND s.active = 1
+             WHERE (?1 IS NULL OR f.owner = ?1 OR f.owner IS NULL)
+               AND (?2 IS NULL OR EXISTS (
+                   SELECT 1 FROM shares r
+                   WHERE r.uuid = f.uuid AND r.recipient = ?2 COLLATE NOCASE AND (?3 = 0 OR r.active = 1)))
+               AND (?4 IS NULL OR f.date_added >= ?4)
+               AND (?5 IS NULL OR f.date_added < ?5)
+               AND (?6 IS NULL OR LOWER(f.filename) GLOB LOWER(?6))
+             GROUP BY f.uuid
+             HAVING (?3 = 0 OR share_count > 0) AND (?7 = 0 OR share_count = 0)
+             ORDER BY {} {}, f.date_added DESC, f.uuid
+             LIMIT ?8 OFFSET ?9",
+            FileShare::COLUMNS, order, direction
+        ))?;
+
+        let owner = (!filter.all).then_some(&self.actor.user);
+        // A negative LIMIT means no limit
+        let limit = filter.limit.map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+        let offset = i64::try_from(filter.offset).unwrap_or(i64::MAX);
+        let rows = stmt.query_map(params![
+            owner,
+            filter.recipient,
+            filter.active_only,
+            filter.since,
+            filter.until,
+            filter.name,
+            filter.no_shares,
+            limit,
+            offset
+        ], |row| {
+            Ok(FileListing {
+                file: FileShare::from_row(row)?,
+                active_shares: row.get(8)?,
+            })
+        })?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Every share of a file, including removed ones.
+    pub fn shares(&self, uuid: &str) -> Result<Vec<ShareInfo>> {
+        ShareInfo::get_shares(&self.conn, uuid)
+    }
+
+    pub fn share_url(&self, file: &FileShare, share_hash: &str) -> String {
+        format!("{}/{}/{}", self.config.base_url, share_hash, file.filename)
+    }
+
+    /// Copies the file at `path` into the store, named `name` or after the
+    /// source file.
+    pub fn add(&self, path: &Path, name: Option<&str>, dedupe: bool) -> Result<AddOutcome> {
+        let filename = match name {
+            Some(name) => sanitize_filename(name)?,
+            None => path.file_name()
+                .ok_or_else(|| invalid!("Invalid filename"))?
+                .to_string_lossy()
+                .to_string(),
+        };
+        let checksum = calculate_file_hash(path)?;
+        self.store(path, &filename, checksum, dedupe, false, None)
+    }
+
+    /// Stores everything read from `reader` as `name`. The data is spooled to
+    /// a temporary file outside BASE_DIR first so it can be hashed and sniffed.
+    pub fn add_reader<R: Read>(&self, reader: R, name: &str, dedupe: bool) -> Result<AddOutcome> {
+        let filename = sanitize_filename(name)?;
+        let (temp, checksum) = spool(reader)?;
+        self.store(temp.path(), &filename, checksum, dedupe, false, None)
+    }
+
+    /// Stores ciphertext written by `crypto::encrypt` as `name`. The key stays
+    /// with the caller, so the data cannot be read back on this side; shares
+    /// of the file publish the ciphertext unchanged.
+    pub fn add_encrypted<R: Read>(&self, reader: R, name: &str) -> Result<FileShare> {
+        let filename = sanitize_filename(name)?;
+        let (temp, checksum) = spool(reader)?;
+        crypto::check_header(fs::File::open(temp.path())?)?;
+        Ok(self.store(temp.path(), &filename, checksum, false, true, None)?.file().clone())
+    }
+
+    /// Stores `source` as a new file owned by the actor or, for a file received
+    /// through `upload`, by the owner of that request.
+    fn store(
+        &self,
+        source: &Path,
+        filename: &str,
+        checksum: String,
+        dedupe: bool,
+        encrypted: bool,
+        upload: Option<&UploadRequest>,
+    ) -> Result<AddOutcome> {
+        // Enable WAL mode for better concurrency
+        self.conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+        let size = fs::metadata(source)?.len();
+        let owner = upload.map_or(self.actor.user.as_str(), |upload| upload.owner.as_str());
+        // With several users, data is only reused among one user's own files, so
+        // no one's upload ends up sharing an inode with, or returning, another's
+        let dedupe_owner = self.config.admin_group.is_some().then_some(owner);
+        let existing = FileShare::find_by_checksum(&self.conn, self.storage(), &checksum, size, dedupe_owner)?;
+
+        if let (true, Some(existing)) = (dedupe, &existing) {
+            return Ok(AddOutcome::Duplicate(existing.clone()));
+        }
+
+        let uuid = Uuid::new_v4().to_string();
+        let mime_type = sniff_mime_type(source)?;
+
+        // Reuse the stored data of an identical file instead of storing another copy
+        let reuse = existing.as_ref().map(|existing| (existing.uuid.as_str(), existing.filename.as_str()));
+        let reused = self.storage.put(&uuid, filename, source, &mime_type, reuse)?;
+        let reused = existing.filter(|_| reused);
+
+        let file = self.logged(|| {
+            self.conn.execute(
+                "INSERT INTO files
+                    (uuid, filename, date_added, checksum, size, mime_type, owner, encrypted, request_hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![uuid, filename, Utc::now(), checksum, size, mime_type, owner, encrypted,
+                    upload.map(|upload| &upload.request_hash)],
+            )?;
+
+            let file = self.file(&uuid)?.ok_or_else(|| anyhow!("File not found"))?;
+            self.log(Event {
+                action: if upload.is_some() { "upload" } else { "add" },
+                uuid: Some(file.uuid.clone()),
+                filename: Some(file.filename.clone()),
+                recipient: upload.map(|upload| upload.recipient.clone()),
+                share_hash: upload.map(|upload| upload.request_hash.clone()),
+                details: Some(format!("BLAKE3 {}{}", file.checksum.as_deref().unwrap_or_default(),
+                    if encrypted { ", encrypted" } else { "" })),
+            })?;
+            Ok(file)
+        })?;
+        Ok(AddOutcome::Added { file, reused })
+    }
+
+    /// Creates or replaces the share of a file with one recipient. Group names
+    /// are not expanded here; see `expand_recipients`. Shares of an encrypted
+    /// file are encrypted; their links need the key appended as `#key`.
+    pub fn share(&self, uuid: &str, recipient: &str, options: &ShareOptions) -> Result<ShareInfo> {
+        if options.max_downloads == Some(0) {
+            return Err(invalid!("Download limit must be at least 1"));
+        }
+        if options.encrypt && options.password.is_some() {
+            return Err(invalid!("Encrypted shares cannot have a password"));
+        }
+        let file = self.file(uuid)?.ok_or_else(|| invalid!("File not found: {}", uuid))?;
+        self.check_owner(&file)?;
+        if options.encrypt && !file.encrypted {
+            return Err(invalid!("{} is stored unencrypted; add it with `slink add --encrypt` \
+                to share it encrypted", file.filename));
+        }
+
+        let mut restrictions = Vec::new();
+        if let Some(expires) = options.expires {
+            restrictions.push(format!("expires {}", expires.to_rfc3339()));
+        }
+        if let Some(max_downloads) = options.max_downloads {
+            restrictions.push(format!("download limit {}", max_downloads));
+        }
+        if options.password.is_some() {
+            restrictions.push("password".to_string());
+        }
+        if file.encrypted {
+            restrictions.push("encrypted".to_string());
+        }
+        self.logged(|| {
+            ShareInfo::share(&self.conn, &self.config, self.storage(), &file, recipient, options, &self.actor.user)?;
+            self.log(Event {
+                details: Some(restrictions.join(", ")).filter(|details| !details.is_empty()),
+                ..self.share_event("share", uuid, recipient)?
+            })
+        })?;
+
+        self.shares(uuid)?
+            .into_iter()
+            .find(|share| share.recipient == recipient)
+            .ok_or_else(|| invalid!("Share not found"))
+    }
+
+    /// Removes a share. Besides the file's owner and admins, whoever created
+    /// the share may remove it.
+    pub fn unshare(&self, uuid: &str, recipient: &str) -> Result<()> {
+        if let Some(file) = self.file(uuid)? {
+            let shared_by_actor = self.shares(uuid)?.iter()
+                .any(|share| share.recipient == recipient && share.owner.as_ref() == Some(&self.actor.user));
+            if !shared_by_actor {
+                self.check_owner(&file)?;
+            }
+        }
+        self.logged(|| {
+            ShareInfo::unshare(&self.conn, self.storage(), uuid, recipient)?;
+            self.log(self.share_event("unshare", uuid, recipient)?)
+        })
+    }
+
+    /// Deletes a file, its links and its database entry. Share history is kept,
+    /// and the audit log still names the file.
+    pub fn remove(&self, uuid: &str) -> Result<FileShare> {
+        let file = self.file(uuid)?.ok_or_else(|| invalid!("File not found: {}", uuid))?;
+        self.check_owner(&file)?;
+        self.logged(|| {
+            file.remove(&self.conn, self.storage())?;
+            self.log(Event {
+                action: "rm",
+                uuid: Some(file.uuid.clone()),
+                filename: Some(file.filename.clone()),
+                details: file.checksum.as_ref().map(|checksum| format!("BLAKE3 {}", checksum)),
+                ..Event::default()
+            })
+        })?;
+        Ok(file)
+    }
+
+    /// Hands a file over to `owner`. Admins only.
+    pub fn set_owner(&self, uuid: &str, owner: &str) -> Result<FileShare> {
+        self.check_admin("change the owner of a file")?;
+        if users::get_user_by_name(owner).is_none() {
+            return Err(invalid!("Unknown user: {}", owner));
+        }
+        let file = self.file(uuid)?.ok_or_else(|| invalid!("File not found: {}", uuid))?;
+        self.logged(|| {
+            self.conn.execute("UPDATE files SET owner = ? WHERE uuid = ?", params![owner, uuid])?;
+            self.log(Event {
+                action: "chown",
+                uuid: Some(file.uuid.clone()),
+                filename: Some(file.filename.clone()),
+                details: Some(format!("from {} to {}", file.owner.as_deref().unwrap_or("nobody"), owner)),
+                ..Event::default()
+            })
+        })?;
+        self.file(uuid)?.ok_or_else(|| invalid!("File not found: {}", uuid))
+    }
+
+    /// Expands `@group` to its members; any other recipient is returned as is.
+    pub fn expand_recipients(&self, recipient: &str) -> Result<Vec<String>> {
+        RecipientGroup::expand(&self.conn, recipient)
+    }
+
+    pub fn groups(&self) -> Result<Vec<RecipientGroup>> {
+        RecipientGroup::find_all(&self.conn)
+    }
+
+    /// Looks up a group by name, with or without the leading `@`.
+    pub fn group(&self, name: &str) -> Result<Option<RecipientGroup>> {
+        RecipientGroup::find_by_name(&self.conn, name)
+    }
+
+    /// Returns the number of recipients that were not already members. A new
+    /// group belongs to the actor.
+    pub fn add_group_members(&self, name: &str, recipients: &[String]) -> Result<usize> {
+        let owner = match self.group(name)? {
+            Some(group) => {
+                self.check_group_owner(&group)?;
+                group.owner.unwrap_or_else(|| self.actor.user.clone())
+            }
+            None => self.actor.user.clone(),
+        };
+        self.logged(|| {
+            let added = RecipientGroup::add_members(&self.conn, name, recipients, &owner)?;
+            self.log(Event {
+                action: "group-add",
+                details: Some(format!("@{}: {}", name.trim_start_matches('@'), recipients.join(", "))),
+                ..Event::default()
+            })?;
+            Ok(added)
+        })
+    }
+
+    /// Removes the given members, or the whole group when `recipients` is empty.
+    pub fn remove_group_members(&self, name: &str, recipients: &[String]) -> Result<usize> {
+        if let Some(group) = self.group(name)? {
+            self.check_group_owner(&group)?;
+        }
+        let members = if recipients.is_empty() { "all members".to_string() } else { recipients.join(", ") };
+        self.logged(|| {
+            let removed = RecipientGroup::remove_members(&self.conn, name, recipients)?;
+            self.log(Event {
+                action: "group-rm",
+                details: Some(format!("@{}: {}", name.trim_start_matches('@'), members)),
+                ..Event::default()
+            })?;
+            Ok(removed)
+        })
+    }
+
+    /// Creates a link through which `recipient` can send files to the actor,
+    /// see `receive_upload`.
+    pub fn request_upload(
+        &self,
+        recipient: &str,
+        name_hint: Option<&str>,
+        expires: Option<DateTime<Utc>>,
+    ) -> Result<UploadRequest> {
+        let name_hint = name_hint.map(sanitize_filename).transpose()?;
+        self.logged(|| {
+            let request = UploadRequest::create(
+                &self.conn, &self.config, recipient, name_hint.as_deref(), expires, &self.actor.user)?;
+            self.log(Event {
+                action: "request",
+                recipient: Some(recipient.to_string()),
+                share_hash: Some(request.request_hash.clone()),
+                details: expires.map(|expires| format!("expires {}", expires.to_rfc3339())),
+                ..Event::default()
+            })?;
+            Ok(request)
+        })
+    }
+
+    /// The actor's upload requests, or with `all` everyone's, newest first.
+    pub fn upload_requests(&self, all: bool) -> Result<Vec<UploadRequest>> {
+        let owner = (!all).then_some(self.actor.user.as_str());
+        UploadRequest::find_all(&self.conn, owner)
+    }
+
+    pub fn upload_request(&self, request_hash: &str) -> Result<Option<UploadRequest>> {
+        UploadRequest::find_by_hash(&self.conn, request_hash)
+    }
+
+    /// Stops accepting uploads for a request. Files already received are kept.
+    pub fn close_upload_request(&self, request_hash: &str) -> Result<UploadRequest> {
+        let request = self.upload_request(request_hash)?
+            .ok_or_else(|| invalid!("Upload request not found: {}", request_hash))?;
+        if request.owner != self.actor.user {
+            self.check_admin("close upload requests of other users")?;
+        }
+        self.logged(|| {
+            UploadRequest::close(&self.conn, request_hash)?;
+            self.log(Event {
+                action: "request-close",
+                recipient: Some(request.recipient.clone()),
+                share_hash: Some(request.request_hash.clone()),
+                ..Event::default()
+            })
+        })?;
+        self.upload_request(request_hash)?.ok_or_else(|| invalid!("Upload request not found"))
+    }
+
+    pub fn upload_url(&self, request: &UploadRequest) -> String {
+        format!("{}/upload/{}/", self.config.base_url, request.request_hash)
+    }
+
+    /// Stores a file sent through an open upload request like `add_reader`
+    /// does, as `name` or else the request's name hint, linked to the request.
+    /// The file belongs to whoever created the request.
+    pub fn receive_upload<R: Read>(&self, request_hash: &str, reader: R, name: Option<&str>) -> Result<FileShare> {
+        let request = self.upload_request(request_hash)?
+            .filter(UploadRequest::is_open)
+            .ok_or_else(|| invalid!("No open upload request {}", request_hash))?;
+        let name = name.or(request.name_hint.as_deref())
+            .ok_or_else(|| invalid!("The upload needs a file name"))?;
+
+        let filename = sanitize_filename(name)?;
+        let (temp, checksum) = spool(reader)?;
+        Ok(self.store(temp.path(), &filename, checksum, false, false, Some(&request))?.file().clone())
+    }
+
+    /// Creates a token for `slink api` acting as `user`, the actor by default;
+    /// only admins may create tokens for others. Returns the token with its
+    /// secret, which cannot be shown again.
+    pub fn create_token(&self, name: &str, user: Option<&str>) -> Result<(ApiToken, String)> {
+        let user = user.unwrap_or(&self.actor.user);
+        if user != self.actor.user {
+            self.check_admin("create tokens for other users")?;
+        }
+        if users::get_user_by_name(user).is_none() {
+            return Err(invalid!("Unknown user: {}", user));
+        }
+        self.logged(|| {
+            let (token, secret) = ApiToken::create(&self.conn, name, user)?;
+            self.log(Event {
+                action: "token-create",
+                details: Some(format!("API token {} for {}", token.name, token.user)),
+                ..Event::default()
+            })?;
+            Ok((token, secret))
+        })
+    }
+
+    /// The actor's API tokens, or every token for admins.
+    pub fn tokens(&self) -> Result<Vec<ApiToken>> {
+        let user = (!self.is_admin()).then_some(self.actor.user.as_str());
+        ApiToken::find_all(&self.conn, user)
+    }
+
+    /// Revokes an API token. Users may revoke their own tokens, admins any.
+    pub fn revoke_token(&self, name: &str) -> Result<ApiToken> {
+        let token = ApiToken::find_by_name(&self.conn, name)?
+            .ok_or_else(|| invalid!("Token not found: {}", name))?;
+        if token.user != self.actor.user {
+            self.check_admin("revoke tokens of other users")?;
+        }
+        self.logged(|| {
+            ApiToken::revoke(&self.conn, name)?;
+            self.log(Event {
+                action: "token-revoke",
+                details: Some(format!("API token {} for {}", token.name, token.user)),
+                ..Event::default()
+            })
+        })?;
+        Ok(token)
+    }
+
+    /// The API token whose secret is `secret`, if any.
+    pub fn authenticate_token(&self, secret: &str) -> Result<Option<ApiToken>> {
+        ApiToken::authenticate(&self.conn, secret)
+    }
+
+    /// Deactivates shares past their expiry and returns their (uuid, recipient) pairs.
+    pub fn expire_overdue(&self) -> Result<Vec<(String, String)>> {
+        self.logged(|| {
+            let expired = ShareInfo::expire_overdue(&self.conn, self.storage())?;
+            for (uuid, recipient) in &expired {
+                self.log(self.share_event("expire", uuid, recipient)?)?;
+            }
+            Ok(expired)
+        })
+    }
+
+    /// Removes links from a previous hash secret whose grace period has ended.
+    pub fn expire_retired_links(&self) -> Result<usize> {
+        self.logged(|| {
+            let removed = ShareInfo::expire_retired_links(&self.conn, self.storage())?;
+            if removed > 0 {
+                self.log(Event {
+                    action: "expire-links",
+                    details: Some(format!("{} link(s) from a previous hash secret", removed)),
+                    ..Event::default()
+                })?;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// Generates a new hash secret, moves every active share to its new link and
+    /// saves the configuration to `config_path`. With `grace_until` the old links
+    /// keep working until then, otherwise they are removed right away.
+    pub fn rotate_secret(
+        &mut self,
+        grace_until: Option<DateTime<Utc>>,
+        config_path: &Path,
+    ) -> Result<Vec<RotatedShare>> {
+        self.check_admin("rotate the hash secret")?;
+        if std::env::var_os("SLINK_HASH_SECRET").is_some() {
+            return Err(invalid!("The hash secret is set by SLINK_HASH_SECRET; change it there instead"));
+        }
+        let new_config = Config {
+            hash_secret: Uuid::new_v4().to_string(),
+            ..self.config.clone()
+        };
+
+        let config = &self.config;
+        let actor = &self.actor;
+        // Logged in the transaction that moves the shares
+        let record = |conn: &Connection, rotated: &[RotatedShare]| -> Result<()> {
+            for share in rotated {
+                audit::record(conn, actor, Event {
+                    action: "relink",
+                    uuid: Some(share.uuid.clone()),
+                    filename: FileShare::find_by_uuid(conn, &share.uuid)?.map(|file| file.filename),
+                    recipient: Some(share.recipient.clone()),
+                    share_hash: Some(share.new_hash.clone()),
+                    details: Some(format!("previous link {}", share.old_hash)),
+                })?;
+            }
+            audit::record(conn, actor, Event {
+                action: "rotate-secret",
+                details: Some(match grace_until {
+                    Some(until) => format!("{} share(s) moved, old links valid until {}",
+                        rotated.len(), until.to_rfc3339()),
+                    None => format!("{} share(s) moved, old links removed", rotated.len()),
+                }),
+                ..Event::default()
+            })
+        };
+        let rotated = ShareInfo::rotate_secret(
+            &mut self.conn, config, self.storage.as_ref(), &new_config.hash_secret, grace_until,
+            record, |secret| config.save_hash_secret(config_path, secret))?;
+
+        // Only drop the old links once the new secret is safely stored
+        if grace_until.is_none() {
+            for share in &rotated {
+                self.storage.unpublish(&share.old_hash)?;
+            }
+        }
+
+        self.config = new_config;
+        Ok(rotated)
+    }
+
+    /// Audit log entries, oldest first, optionally only those about file
+    /// `uuid` or share link `share_hash`.
+    pub fn audit_log(&self, uuid: Option<&str>, share_hash: Option<&str>) -> Result<Vec<AuditEntry>> {
+        audit::entries(&self.conn, uuid, share_hash)
+    }
+
+    /// Checks the hash chain of the audit log.
+    pub fn verify_audit_log(&self) -> Result<ChainVerification> {
+        audit::verify(&self.conn)
+    }
+
+    /// Rehashes the stored copy of `file` and compares it with the recorded
+    /// checksum and size.
+    pub fn verify(&self, file: &FileShare) -> Result<Verification> {
+        let reader = match self.storage.read(&file.uuid, &file.filename, None)? {
+            Some(reader) => reader,
+            None => return Ok(Verification::Missing),
+        };
+        let expected = match &file.checksum {
+            Some(checksum) => checksum,
+            None => return Ok(Verification::NoChecksum),
+        };
+
+        let (actual, size) = hash_reader(reader)?;
+        Ok(if &actual != expected {
+            Verification::ChecksumMismatch { actual }
+        } else if file.size.is_some_and(|s| s != size) {
+            Verification::SizeMismatch { actual: size }
+        } else {
+            Verification::Ok
+        })
+    }
+
+    /// Reads a combined (or common) format access log from `path`, or stdin when
+    /// `path` is `-`, and records every request that targets a share.
+    /// `on_revoke(filename, recipient)` is called as soon as a share reaches its
+    /// download limit and is removed.
+    pub fn ingest_log(&self, path: &str, on_revoke: impl FnMut(&str, &str)) -> Result<IngestReport> {
+        ingest::ingest_log(&self.conn, self.storage(), &self.actor, path, on_revoke)
+    }
+
+    /// Compares the database with BASE_DIR and, if `repair` is set, fixes what
+    /// it can. Only admins may repair.
+    pub fn fsck(&self, repair: bool) -> Result<FsckReport> {
+        if repair {
+            self.check_admin("repair")?;
+        }
+        fsck::fsck(&self.conn, &self.config, self.storage(), &self.actor, repair)
+    }
+
+    pub fn statistics(&self) -> Result<Statistics> {
+        let count = |sql: &str| -> Result<i64> {
+            self.conn.query_row(sql, [], |row| row.get(0)).map_err(Into::into)
+        };
+
+        Ok(Statistics {
+            file_count: count("SELECT COUNT(*) FROM files")?,
+            total_shares: count("SELECT COUNT(*) FROM shares")?,
+            active_shares: count("SELECT COUNT(*) FROM shares WHERE active = 1")?,
+            oldest_file: self.conn.query_row(
+                "SELECT MIN(date_added) FROM files",
+                [],
+                |row| row.get::<_, Option<DateTime<Utc>>>(0),
+            )?,
+        })
+    }
+}
+
+fn sanitize_filename(name: &str) -> Result<String> {
+    let name = name.trim();
+
+    // Basic security checks
+    if name.is_empty() {
+        return Err(invalid!("Empty filename not allowed"));
+    }
+
+    if name.contains('/') || name.contains('\\') || name.contains("..") {
+        return Err(invalid!("Invalid characters in filename"));
+    }
+
+    // Remove any leading dots to prevent hidden files
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        return Err(invalid!("Invalid filename (hidden files not allowed)"));
+    }
+
+    // Additional checks for problematic characters
+    if name.chars().any(|c| {
+        c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*')
+    }) {
+        return Err(invalid!("Invalid characters in filename"));
+    }
+
+    Ok(name.to_string())
+}
+
+/// Detects the MIME type from the file's leading bytes, falling back to
+/// `text/plain` for valid UTF-8 and `application/octet-stream` otherwise.
+fn sniff_mime_type(path: &Path) -> Result<String> {
+    let mut head = Vec::with_capacity(8192);
+    fs::File::open(path)?.take(8192).read_to_end(&mut head)?;
+
+    if let Some(kind) = infer::get(&head) {
+        return Ok(kind.mime_type().to_string());
+    }
+
+    // A multi-byte character may be cut off at the end of the sample
+    let is_text = match std::str::from_utf8(&head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    Ok(if is_text && !head.is_empty() { "text/plain" } else { "application/octet-stream" }.to_string())
+}
+
+fn calculate_file_hash(path: &Path) -> Result<String> {
+    hash_reader(fs::File::open(path)?).map(|(hash, _)| hash)
+}
+
+/// BLAKE3 of everything `reader` yields, and the number of bytes read.
+fn hash_reader<R: Read>(mut reader: R) -> Result<(String, u64)> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = [0; 8192];
+    let mut size = 0;
+
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buffer[..n]);
+                size += n as u64;
+            }
+            Err(e) => return Err(anyhow!("Error reading file for hash: {}", e)),
+        };
+    }
+
+    Ok((hasher.finalize().to_hex().to_string(), size))
+}
+
+/// The actor may not make a change, as opposed to the change being invalid or
+/// failing. Callers such as the API can find it with `downcast_ref`.
+#[derive(Debug)]
+pub struct PermissionDenied(String);
+
+impl fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
+
+/// A mistake in what the caller asked for: a malformed or out-of-range value,
+/// or a file, share, group or user that does not exist. Other errors are
+/// failures of the database, the storage or the system. Created with
+/// `invalid!`.
+#[derive(Debug)]
+pub struct InvalidInput(pub(crate) String);
+
+impl fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidInput {}
+
+/// Copies everything read from `reader` to a temporary file, which is removed
+/// when dropped, and returns it with the BLAKE3 of the data. The file is
+/// created in the system temp directory (TMPDIR), never in BASE_DIR where the
+/// web server could serve it.
+fn spool<R: Read>(mut reader: R) -> Result<(NamedTempFile, String)> {
+    let temp = tempfile::Builder::new().prefix("slink_temp_").tempfile()?;
+    let mut writer = BufWriter::new(temp.as_file());
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = [0; 8192];
+
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                writer.write_all(&buffer[..n])?;
+                hasher.update(&buffer[..n]);
+            }
+            Err(e) => return Err(invalid!("Error reading input: {}", e)),
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+
+    Ok((temp, hasher.finalize().to_hex().to_string()))
+}
@@ -0,0 +1,87 @@
+// tests/mail.rs
+//! Delivers a share notification to a minimal SMTP sink on localhost.
+use slink::mail::{send_share_notification, ShareNotification};
+use slink::MailConfig;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+
+/// Accepts one SMTP session and returns the envelope commands and message
+/// data it received.
+fn smtp_sink(listener: TcpListener) -> thread::JoinHandle<(Vec<String>, String)> {
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut commands = Vec::new();
+        let mut data = String::new();
+
+        writer.write_all(b"220 sink ESMTP\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let command = line.trim_end().to_string();
+            let verb = command.split_whitespace().next().unwrap_or_default().to_ascii_uppercase();
+            commands.push(command);
+            match verb.as_str() {
+                "EHLO" | "HELO" => writer.write_all(b"250 sink\r\n").unwrap(),
+                "DATA" => {
+                    writer.write_all(b"354 go ahead\r\n").unwrap();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&line);
+                    }
+                    writer.write_all(b"250 queued\r\n").unwrap();
+                }
+                "QUIT" => {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                }
+                _ => writer.write_all(b"250 ok\r\n").unwrap(),
+            }
+        }
+        (commands, data)
+    })
+}
+
+#[test]
+fn delivers_notification_over_smtp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let sink = smtp_sink(listener);
+
+    let mail = MailConfig {
+        from: "Files <files@example.com>".to_string(),
+        smtp_host: Some("127.0.0.1".to_string()),
+        smtp_port: Some(port),
+        smtp_security: Some("none".to_string()),
+        smtp_username: None,
+        smtp_password: None,
+        sendmail_path: None,
+        subject: None,
+        body: None,
+    };
+    let notification = ShareNotification {
+        recipient: "alice@example.com",
+        filename: "report.pdf",
+        size: Some("1.2 MiB".to_string()),
+        url: "http://localhost:8080/KJh8h7G6dT/report.pdf",
+        expires: None,
+        max_downloads: Some(1),
+        password_protected: false,
+    };
+    send_share_notification(&mail, &notification).unwrap();
+
+    let (commands, data) = sink.join().unwrap();
+    assert!(commands.iter().any(|c| c == "MAIL FROM:<files@example.com>"));
+    assert!(commands.iter().any(|c| c == "RCPT TO:<alice@example.com>"));
+    assert!(data.contains("Subject: Files shared report.pdf with you"));
+    assert!(data.contains("http://localhost:8080/KJh8h7G6dT/report.pdf"));
+    assert!(data.contains("It can be downloaded 1 time(s)."));
+}
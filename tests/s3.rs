@@ -0,0 +1,61 @@
+// tests/s3.rs
+//! Round trip through a real S3-compatible server. Opt-in: start MinIO, create
+//! a bucket and run
+//!
+//!     SLINK_TEST_S3_ENDPOINT=http://127.0.0.1:9000 SLINK_TEST_S3_BUCKET=slink-test \
+//!     SLINK_TEST_S3_ACCESS_KEY=minioadmin SLINK_TEST_S3_SECRET_KEY=minioadmin \
+//!     cargo test --test s3 -- --ignored
+use slink::s3::S3Storage;
+use slink::storage::Storage;
+use slink::S3Config;
+use std::io::{Read, Write};
+
+fn env(name: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| panic!("{} must be set for the S3 test", name))
+}
+
+fn read_all(reader: Box<dyn Read + Send>) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut reader = reader;
+    reader.read_to_end(&mut data).unwrap();
+    data
+}
+
+#[test]
+#[ignore = "needs an S3-compatible server, see the top of this file"]
+fn minio_round_trip() {
+    let storage = S3Storage::new(&S3Config {
+        endpoint: env("SLINK_TEST_S3_ENDPOINT"),
+        bucket: env("SLINK_TEST_S3_BUCKET"),
+        region: std::env::var("SLINK_TEST_S3_REGION").ok(),
+        access_key: env("SLINK_TEST_S3_ACCESS_KEY"),
+        secret_key: env("SLINK_TEST_S3_SECRET_KEY"),
+        path_style: None,
+    }).unwrap();
+
+    let uuid = uuid::Uuid::new_v4().to_string();
+    let copy = uuid::Uuid::new_v4().to_string();
+    let share_hash = format!("test-{}", &uuid[..8]);
+    let filename = "annual report.txt";
+    let mut source = tempfile::NamedTempFile::new().unwrap();
+    source.write_all(b"0123456789abcdef").unwrap();
+
+    assert!(!storage.put(&uuid, filename, source.path(), "text/plain", None).unwrap());
+    assert_eq!(storage.size(&uuid, filename).unwrap(), Some(16));
+    assert_eq!(read_all(storage.read(&uuid, filename, None).unwrap().unwrap()), b"0123456789abcdef");
+    assert_eq!(read_all(storage.read(&uuid, filename, Some((2, 5))).unwrap().unwrap()), b"2345");
+
+    // Identical data is copied server-side
+    assert!(storage.put(&copy, filename, source.path(), "text/plain", Some((&uuid, filename))).unwrap());
+    assert_eq!(storage.size(&copy, filename).unwrap(), Some(16));
+
+    storage.publish(&share_hash, &uuid, filename).unwrap();
+    assert_eq!(storage.size(&share_hash, filename).unwrap(), Some(16));
+    storage.unpublish(&share_hash).unwrap();
+    assert_eq!(storage.size(&share_hash, filename).unwrap(), None);
+
+    storage.remove(&uuid, filename).unwrap();
+    storage.remove(&copy, filename).unwrap();
+    assert_eq!(storage.size(&uuid, filename).unwrap(), None);
+    assert!(storage.read(&uuid, filename, None).unwrap().is_none());
+}
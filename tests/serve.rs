@@ -0,0 +1,71 @@
+// tests/serve.rs
+//! Requests against `slink serve` for a download-limited share.
+use slink::{init_database, serve, Config, ShareOptions, Store};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// A store in a temporary directory, with `base_url` pointing at `port`.
+fn store(dir: &tempfile::TempDir, port: u16) -> Store {
+    let user = users::get_current_username().unwrap().to_string_lossy().to_string();
+    let group = users::get_current_groupname().unwrap().to_string_lossy().to_string();
+    let base_dir = dir.path().join("www");
+    std::fs::create_dir(&base_dir).unwrap();
+    let db_path = dir.path().join("shares.db").to_string_lossy().to_string();
+    init_database(&db_path).unwrap();
+
+    Store::open(Config {
+        base_url: format!("http://127.0.0.1:{}", port),
+        base_dir: base_dir.to_string_lossy().to_string(),
+        db_path,
+        hash_secret: "0123456789abcdef0123".to_string(),
+        web_user: user,
+        web_group: group,
+        hash_bytes: 7,
+        admin_group: None,
+        mail: None,
+        storage: None,
+        profile: None,
+    }).unwrap()
+}
+
+/// Sends a GET request with the given extra headers and returns the status
+/// code and body.
+fn get(port: u16, path: &str, headers: &str) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n", path, headers).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&response[..split]).to_string();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, response[split + 4..].to_vec())
+}
+
+#[test]
+fn range_requests_count_against_the_download_limit() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let dir = tempfile::tempdir().unwrap();
+    let store = store(&dir, port);
+
+    let file = store.add_reader(&b"0123456789abcdef"[..], "report.txt", false).unwrap().file().clone();
+    let options = ShareOptions { max_downloads: Some(1), ..ShareOptions::default() };
+    let share = store.share(&file.uuid, "alice@example.com", &options).unwrap();
+    let path = format!("/{}/report.txt", share.share_hash);
+
+    let config = store.config().clone();
+    thread::spawn(move || serve::serve(config, &format!("127.0.0.1:{}", port), None, 1, 1024));
+    thread::sleep(Duration::from_millis(200));
+
+    // The range is ignored and the whole file sent as the one allowed download
+    let (status, body) = get(port, &path, "Range: bytes=0-\r\n");
+    assert_eq!(status, 200);
+    assert_eq!(body, b"0123456789abcdef");
+
+    let (status, _) = get(port, &path, "Range: bytes=0-\r\n");
+    assert_eq!(status, 404);
+    let (status, _) = get(port, &path, "");
+    assert_eq!(status, 404);
+}